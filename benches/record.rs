@@ -0,0 +1,192 @@
+//! Throughput of folding input events into [`InputState`], directly, through
+//! the mutex the listener callback locks for every event, and with mouse moves
+//! filtered out before the lock as with `track_mouse_move = false`. For
+//! comparison, the six plain counters are also kept in atomics updated with
+//! `fetch_add` and snapshot with `swap`, once on their own and once next to the
+//! locked state the other fields would still need.
+//!
+//! Run with `cargo bench`.
+
+use aw_watcher_input_rs::{InputState, Tracking};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use rdev::{Button, EventType, Key};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Number of events folded per iteration
+const EVENTS: usize = 100_000;
+
+/// A mixed stream dominated by mouse moves, like the input of a mouse with a
+/// high polling rate with some typing, clicking and scrolling in between
+fn synthetic_events(count: usize) -> Vec<EventType> {
+    (0..count)
+        .map(|n| match n % 20 {
+            0 => EventType::KeyPress(Key::KeyA),
+            1 => EventType::KeyRelease(Key::KeyA),
+            2 => EventType::ButtonPress(Button::Left),
+            3 => EventType::ButtonRelease(Button::Left),
+            4 => EventType::Wheel {
+                delta_x: 0,
+                delta_y: -1,
+            },
+            _ => EventType::MouseMove {
+                x: (n % 1920) as f64,
+                y: (n % 1080) as f64,
+            },
+        })
+        .collect()
+}
+
+/// The counters `presses`, `clicks`, `deltaX`, `deltaY`, `scrollX` and
+/// `scrollY` as atomics, so the listener could update them without the lock
+#[derive(Default)]
+struct AtomicCounters {
+    presses: AtomicU64,
+    clicks: AtomicU64,
+    delta_x: AtomicU64,
+    delta_y: AtomicU64,
+    scroll_x: AtomicU64,
+    scroll_y: AtomicU64,
+}
+
+impl AtomicCounters {
+    /// Count an event. The previous cursor position is only used by the
+    /// listener thread, so it lives outside the shared state.
+    fn record(&self, event_type: &EventType, last_position: &mut Option<(f64, f64)>) {
+        match *event_type {
+            EventType::KeyPress(_) => {
+                self.presses.fetch_add(1, Ordering::Relaxed);
+            }
+            EventType::ButtonPress(_) => {
+                self.clicks.fetch_add(1, Ordering::Relaxed);
+            }
+            EventType::MouseMove { x, y } => {
+                if let Some((last_x, last_y)) = last_position.replace((x, y)) {
+                    let (dx, dy) = ((x - last_x).abs() as u64, (y - last_y).abs() as u64);
+                    self.delta_x.fetch_add(dx, Ordering::Relaxed);
+                    self.delta_y.fetch_add(dy, Ordering::Relaxed);
+                }
+            }
+            EventType::Wheel { delta_x, delta_y } => {
+                self.scroll_x
+                    .fetch_add(delta_x.unsigned_abs(), Ordering::Relaxed);
+                self.scroll_y
+                    .fetch_add(delta_y.unsigned_abs(), Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+
+    /// Take the counters and reset them, as the main loop would every interval
+    fn take(&self) -> [u64; 6] {
+        [
+            &self.presses,
+            &self.clicks,
+            &self.delta_x,
+            &self.delta_y,
+            &self.scroll_x,
+            &self.scroll_y,
+        ]
+        .map(|counter| counter.swap(0, Ordering::Relaxed))
+    }
+}
+
+fn record(c: &mut Criterion) {
+    let events = synthetic_events(EVENTS);
+    let mut group = c.benchmark_group("record");
+    group.throughput(Throughput::Elements(EVENTS as u64));
+
+    group.bench_function("direct", |b| {
+        b.iter_batched(
+            InputState::default,
+            |mut state| {
+                for event_type in &events {
+                    state.record(black_box(*event_type));
+                }
+                state
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("mutex", |b| {
+        b.iter_batched(
+            || Arc::new(Mutex::new(InputState::default())),
+            |state| {
+                for event_type in &events {
+                    if let Ok(mut state_guard) = state.lock() {
+                        state_guard.record(black_box(*event_type));
+                    }
+                }
+                state
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("atomic", |b| {
+        b.iter_batched(
+            || Arc::new(AtomicCounters::default()),
+            |counters| {
+                let mut last_position = None;
+                for event_type in &events {
+                    counters.record(black_box(event_type), &mut last_position);
+                }
+                counters.take()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    // Distance, the active slices, holds, drags and the other derived fields
+    // still need the lock for the same events
+    group.bench_function("atomic_with_mutex", |b| {
+        b.iter_batched(
+            || {
+                (
+                    Arc::new(AtomicCounters::default()),
+                    Arc::new(Mutex::new(InputState::default())),
+                )
+            },
+            |(counters, state)| {
+                let mut last_position = None;
+                for event_type in &events {
+                    counters.record(black_box(event_type), &mut last_position);
+                    if let Ok(mut state_guard) = state.lock() {
+                        state_guard.record(black_box(*event_type));
+                    }
+                }
+                (counters.take(), state)
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    let without_moves = Tracking {
+        mouse_move: false,
+        ..Tracking::default()
+    };
+    group.bench_function("mutex_without_moves", |b| {
+        b.iter_batched(
+            || Arc::new(Mutex::new(InputState::default())),
+            |state| {
+                for event_type in &events {
+                    if !without_moves.tracks(event_type) {
+                        continue;
+                    }
+                    if let Ok(mut state_guard) = state.lock() {
+                        state_guard.record(black_box(*event_type));
+                    }
+                }
+                state
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, record);
+criterion_main!(benches);
@@ -0,0 +1,169 @@
+//! SQLite storage: a durable queue of unsent heartbeats, so events recorded
+//! while aw-server is unreachable survive crashes and restarts, and a
+//! permanent log of input events for the `sqlite` sink.
+
+use aw_models::Event;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+
+/// Heartbeats waiting for the server, oldest first
+pub struct EventBuffer {
+    connection: Connection,
+}
+
+impl EventBuffer {
+    /// Open the buffer at `path`, creating the database if needed. Events left
+    /// over from an earlier run are kept and sent first.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let connection = Connection::open(path).map_err(|e| e.to_string())?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS events (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    event TEXT NOT NULL,
+                    pulsetime REAL NOT NULL
+                )",
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(Self { connection })
+    }
+
+    /// Add an event to the back of the buffer
+    pub fn push(&self, event: &Event, pulsetime: f64) -> Result<(), String> {
+        let event = serde_json::to_string(event).map_err(|e| e.to_string())?;
+        self.connection
+            .execute(
+                "INSERT INTO events (event, pulsetime) VALUES (?1, ?2)",
+                params![event, pulsetime],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Send buffered events oldest first, deleting each once it was sent and
+    /// stopping at the first failure
+    pub fn flush(
+        &self,
+        mut send: impl FnMut(&Event, f64) -> Result<(), String>,
+    ) -> Result<(), String> {
+        while let Some((id, event, pulsetime)) = self.front()? {
+            send(&event, pulsetime)?;
+            self.connection
+                .execute("DELETE FROM events WHERE id = ?1", params![id])
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Number of buffered events
+    pub fn len(&self) -> usize {
+        self.connection
+            .query_row("SELECT COUNT(*) FROM events", [], |row| row.get(0))
+            .unwrap_or(0)
+    }
+
+    fn front(&self) -> Result<Option<(i64, Event, f64)>, String> {
+        let row: Option<(i64, String, f64)> = self
+            .connection
+            .query_row(
+                "SELECT id, event, pulsetime FROM events ORDER BY id LIMIT 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()
+            .map_err(|e| e.to_string())?;
+        row.map(|(id, event, pulsetime)| {
+            serde_json::from_str(&event)
+                .map(|event| (id, event, pulsetime))
+                .map_err(|e| e.to_string())
+        })
+        .transpose()
+    }
+}
+
+/// Every input event, kept for local analysis
+pub struct EventLog {
+    connection: Connection,
+}
+
+impl EventLog {
+    /// Open the log at `path`, creating the database if needed
+    pub fn open(path: &Path) -> Result<Self, String> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let connection = Connection::open(path).map_err(|e| e.to_string())?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS event_log (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    timestamp TEXT NOT NULL,
+                    duration REAL NOT NULL,
+                    data TEXT NOT NULL
+                )",
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(Self { connection })
+    }
+
+    /// Append an event to the log
+    pub fn insert(&self, event: &Event) -> Result<(), String> {
+        let data = serde_json::to_string(&event.data).map_err(|e| e.to_string())?;
+        let duration = event.duration.num_milliseconds() as f64 / 1000.0;
+        self.connection
+            .execute(
+                "INSERT INTO event_log (timestamp, duration, data) VALUES (?1, ?2, ?3)",
+                params![event.timestamp.to_rfc3339(), duration, data],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeDelta, Utc};
+    use serde_json::Map;
+
+    #[test]
+    fn buffer_keeps_events_across_reopening() {
+        let path = std::env::temp_dir().join(format!(
+            "aw-watcher-input-buffer-test-{}.db",
+            std::process::id()
+        ));
+        std::fs::remove_file(&path).ok();
+        let event = |seconds| Event {
+            id: None,
+            timestamp: Utc::now() + TimeDelta::seconds(seconds),
+            duration: TimeDelta::seconds(1),
+            data: Map::new(),
+        };
+
+        let buffer = EventBuffer::open(&path).unwrap();
+        buffer.push(&event(0), 1.1).unwrap();
+        buffer.push(&event(1), 1.1).unwrap();
+        let mut attempts = 0;
+        let result = buffer.flush(|_, _| {
+            attempts += 1;
+            if attempts == 1 {
+                Ok(())
+            } else {
+                Err("unreachable".to_string())
+            }
+        });
+        assert!(result.is_err());
+        drop(buffer);
+
+        // Only the event that failed is left after a restart
+        let buffer = EventBuffer::open(&path).unwrap();
+        assert_eq!(buffer.len(), 1);
+        buffer.flush(|_, _| Ok(())).unwrap();
+        assert_eq!(buffer.len(), 0);
+
+        std::fs::remove_file(&path).ok();
+    }
+}
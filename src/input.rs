@@ -0,0 +1,1080 @@
+use log::debug;
+use rdev::{Button, EventType, Key};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Length of the slices that active_seconds counts
+const ACTIVE_SLICE: Duration = Duration::from_secs(1);
+
+/// Fewer key presses or a shorter typing span than this give no WPM estimate,
+/// since a handful of quick presses would extrapolate to absurd speeds
+const WPM_MIN_PRESSES: u64 = 5;
+const WPM_MIN_SPAN: Duration = Duration::from_secs(1);
+
+/// Cursor travel while a button is held below which the press is still a
+/// click, so that a shaky hand doesn't turn clicks into drags
+const DRAG_MIN_DISTANCE: f64 = 5.0;
+
+/// Cursor travel that counts as one mouse action for the keyboard/mouse ratio,
+/// so that a move weighs about as much as a click or a scroll notch
+const PIXELS_PER_MOUSE_ACTION: f64 = 100.0;
+
+/// Movement is measured over at least this long, since moves reported at the
+/// same instant would give an infinite speed
+const SPEED_MIN_SPAN: Duration = Duration::from_millis(1);
+
+/// A move after a longer pause starts a new movement, instead of its distance
+/// being spread over the pause and giving a speed near zero
+const SPEED_MAX_GAP: Duration = Duration::from_millis(500);
+
+/// rdev reports one unit per wheel notch on Linux and Windows, but the
+/// pixel-based point deltas on macOS, which are roughly ten per notch
+#[cfg(target_os = "macos")]
+const DEFAULT_SCROLL_SCALE: f64 = 0.1;
+#[cfg(not(target_os = "macos"))]
+const DEFAULT_SCROLL_SCALE: f64 = 1.0;
+
+/// How input events are interpreted
+#[derive(Debug, Clone, Copy)]
+pub struct InputOptions {
+    /// Longest gap between two clicks of the same button that counts as a double-click
+    pub double_click_window: Duration,
+    /// Single mouse moves longer than this many pixels are treated as the cursor
+    /// being warped, e.g. to another monitor, and add no distance
+    pub max_mouse_jump: Option<f64>,
+    /// Factor applied to the raw scroll deltas so they come out in wheel notches
+    pub scroll_scale: f64,
+    /// Input after a pause longer than this starts a new burst
+    pub burst_gap: Duration,
+}
+
+impl Default for InputOptions {
+    fn default() -> Self {
+        Self {
+            double_click_window: Duration::from_millis(400),
+            max_mouse_jump: Some(1000.0),
+            scroll_scale: DEFAULT_SCROLL_SCALE,
+            burst_gap: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Input activity accumulated over one polling interval
+#[derive(Debug, Clone)]
+pub struct InputState {
+    pub presses: u64,
+    /// Backspace and Delete presses, also counted in presses
+    pub corrections: u64,
+    /// Enter presses, including the numpad one, also counted in presses
+    pub submits: u64,
+    /// Modifier key presses per family, also counted in presses
+    pub ctrl_presses: u64,
+    pub alt_presses: u64,
+    pub shift_presses: u64,
+    pub meta_presses: u64,
+    /// Caps Lock, Num Lock and Scroll Lock presses, also counted in presses
+    pub lock_toggles: u64,
+    /// Total time keys were held down, for the holds that ended in the interval
+    pub key_hold: Duration,
+    pub clicks: u64,
+    pub clicks_left: u64,
+    pub clicks_right: u64,
+    pub clicks_middle: u64,
+    pub clicks_other: u64,
+    /// Total time mouse buttons were held down, for the holds that ended in the interval
+    pub button_hold: Duration,
+    /// Second clicks of a double-click, also counted in clicks
+    pub double_clicks: u64,
+    pub delta_x: u64,
+    pub delta_y: u64,
+    /// Scroll distance in notches, scaled by [`InputOptions::scroll_scale`]
+    pub scroll_x: f64,
+    pub scroll_y: f64,
+    pub scroll_up: f64,
+    pub scroll_down: f64,
+    pub scroll_left: f64,
+    pub scroll_right: f64,
+    /// Total Euclidean distance travelled by the cursor in pixels
+    pub distance: f64,
+    /// Completed drags, a button press and release with the cursor moving in between
+    pub drags: u64,
+    /// Distance travelled by the cursor during the completed drags in pixels
+    pub drag_distance: f64,
+    /// Lowest and highest cursor speed between two moves in pixels per second,
+    /// `None` until a speed has been measured
+    pub mouse_speed_min: Option<f64>,
+    pub mouse_speed_max: Option<f64>,
+    /// Number of distinct one-second slices of the interval that had any input
+    pub active_seconds: u64,
+    /// Input that followed a pause longer than [`InputOptions::burst_gap`],
+    /// a burst continuing from the previous interval isn't counted again
+    pub bursts: u64,
+    /// Whether any input was seen, also set when only activity is recorded
+    pub active: bool,
+    /// Settings for interpreting the events, kept across intervals
+    pub options: InputOptions,
+    /// Time of the last input of any kind
+    pub last_activity: Instant,
+    /// Time of the last key press
+    pub last_keyboard_activity: Instant,
+    /// Time of the last click, mouse move or scroll
+    pub last_mouse_activity: Instant,
+    /// Cursor position of the previous mouse move, `None` until the first move after a reset
+    last_position: Option<(f64, f64)>,
+    /// Start of the speed measurement in progress and the distance moved since
+    speed_sample: Option<(Instant, f64)>,
+    /// Distance and time over which speeds were measured, for the average
+    speed_distance: f64,
+    speed_time: Duration,
+    /// Start of the interval, slices are counted from here
+    slice_origin: Instant,
+    /// Index of the last slice that had input
+    last_slice: Option<u64>,
+    /// Time of the last input counted towards bursts, `None` before any input
+    /// so that the first one starts a burst
+    burst_activity: Option<Instant>,
+    /// Times of the first and last key press in the interval
+    first_press: Option<Instant>,
+    last_press: Option<Instant>,
+    /// Button and time of the previous click that didn't complete a double-click
+    last_click: Option<(Button, Instant)>,
+    /// Button held down for a possible drag and the distance moved since it was pressed
+    drag: Option<(Button, f64)>,
+    /// Keys and buttons currently held down and when they were pressed
+    held_keys: HashMap<Key, Instant>,
+    held_buttons: HashMap<Button, Instant>,
+}
+
+impl Default for InputState {
+    fn default() -> Self {
+        Self {
+            presses: 0,
+            corrections: 0,
+            submits: 0,
+            ctrl_presses: 0,
+            alt_presses: 0,
+            shift_presses: 0,
+            meta_presses: 0,
+            lock_toggles: 0,
+            key_hold: Duration::ZERO,
+            clicks: 0,
+            clicks_left: 0,
+            clicks_right: 0,
+            clicks_middle: 0,
+            clicks_other: 0,
+            button_hold: Duration::ZERO,
+            double_clicks: 0,
+            delta_x: 0,
+            delta_y: 0,
+            scroll_x: 0.0,
+            scroll_y: 0.0,
+            scroll_up: 0.0,
+            scroll_down: 0.0,
+            scroll_left: 0.0,
+            scroll_right: 0.0,
+            distance: 0.0,
+            drags: 0,
+            drag_distance: 0.0,
+            mouse_speed_min: None,
+            mouse_speed_max: None,
+            active_seconds: 0,
+            bursts: 0,
+            active: false,
+            options: InputOptions::default(),
+            last_activity: Instant::now(),
+            last_keyboard_activity: Instant::now(),
+            last_mouse_activity: Instant::now(),
+            last_position: None,
+            speed_sample: None,
+            speed_distance: 0.0,
+            speed_time: Duration::ZERO,
+            slice_origin: Instant::now(),
+            last_slice: None,
+            burst_activity: None,
+            first_press: None,
+            last_press: None,
+            last_click: None,
+            drag: None,
+            held_keys: HashMap::new(),
+            held_buttons: HashMap::new(),
+        }
+    }
+}
+
+impl InputState {
+    /// Empty state that interprets events according to `options`
+    pub fn new(options: InputOptions) -> Self {
+        Self {
+            options,
+            ..Default::default()
+        }
+    }
+
+    /// Fold a single input event into the state, updating last_activity for
+    /// the events that count as activity
+    pub fn record(&mut self, event_type: EventType) {
+        self.record_at(event_type, Instant::now());
+    }
+
+    /// Only note that input happened, keeping the activity times without any
+    /// counts, for when detailed input data must not be collected. Releases
+    /// still end holds and drags, since a key held when tracking was paused
+    /// may be let go before it resumes.
+    pub fn record_activity(&mut self, event_type: EventType) {
+        let now = Instant::now();
+        self.record_release_at(&event_type, now);
+        self.record_activity_at(&event_type, now);
+    }
+
+    /// Update the activity times, returning whether the event counts as activity
+    fn record_activity_at(&mut self, event_type: &EventType, now: Instant) -> bool {
+        match event_type {
+            EventType::KeyPress(_) => self.last_keyboard_activity = now,
+            EventType::ButtonPress(_) | EventType::MouseMove { .. } | EventType::Wheel { .. } => {
+                self.last_mouse_activity = now
+            }
+            _ => return false,
+        }
+        self.last_activity = now;
+        self.active = true;
+        true
+    }
+
+    /// End the hold or drag of a released key or button
+    fn record_release_at(&mut self, event_type: &EventType, now: Instant) {
+        match *event_type {
+            EventType::KeyRelease(key) => {
+                if let Some(pressed) = self.held_keys.remove(&key) {
+                    self.key_hold = self
+                        .key_hold
+                        .saturating_add(now.saturating_duration_since(pressed));
+                }
+            }
+            EventType::ButtonRelease(button) => self.record_release(button, now),
+            _ => {}
+        }
+    }
+
+    fn record_at(&mut self, event_type: EventType, now: Instant) {
+        // Releases only end holds and drags, they don't count as activity
+        self.record_release_at(&event_type, now);
+        if !self.record_activity_at(&event_type, now) {
+            return;
+        }
+        let new_burst = match self.burst_activity {
+            Some(previous) => now.saturating_duration_since(previous) > self.options.burst_gap,
+            None => true,
+        };
+        if new_burst {
+            self.bursts = self.bursts.saturating_add(1);
+        }
+        self.burst_activity = Some(now);
+        match event_type {
+            EventType::KeyPress(key) => {
+                self.record_key(key);
+                // Auto-repeat sends more presses while the key is held
+                self.held_keys.entry(key).or_insert(now);
+                self.first_press.get_or_insert(now);
+                self.last_press = Some(now);
+            }
+            EventType::ButtonPress(button) => {
+                self.record_click(button, now);
+                self.held_buttons.entry(button).or_insert(now);
+                self.drag.get_or_insert((button, 0.0));
+            }
+            EventType::MouseMove { x, y } => self.record_mouse_move(x, y, now),
+            EventType::Wheel { delta_x, delta_y } => self.record_wheel(delta_x, delta_y),
+            _ => {}
+        }
+
+        // Events arrive in order, so a slice only needs counting the first time
+        let slice = (now.saturating_duration_since(self.slice_origin).as_nanos()
+            / ACTIVE_SLICE.as_nanos()) as u64;
+        if self.last_slice != Some(slice) {
+            self.active_seconds = self.active_seconds.saturating_add(1);
+            self.last_slice = Some(slice);
+        }
+    }
+
+    /// Take the accumulated counters and reset them for the next period,
+    /// keeping only the last activity times, the previous click, holds and
+    /// drags in progress and the settings. A hold is counted in full in the
+    /// interval where it ends.
+    pub fn take(&mut self) -> InputState {
+        let fresh = InputState {
+            last_activity: self.last_activity,
+            last_keyboard_activity: self.last_keyboard_activity,
+            last_mouse_activity: self.last_mouse_activity,
+            // A burst may go on across the interval boundary
+            burst_activity: self.burst_activity,
+            // A double-click may straddle the interval boundary
+            last_click: self.last_click,
+            drag: self.drag,
+            held_keys: std::mem::take(&mut self.held_keys),
+            held_buttons: std::mem::take(&mut self.held_buttons),
+            options: self.options,
+            ..Default::default()
+        };
+        std::mem::replace(self, fresh)
+    }
+
+    /// Estimated typing speed in words per minute, counting five key presses as
+    /// a word. `None` when there were too few presses for a meaningful figure.
+    pub fn wpm(&self) -> Option<f64> {
+        let (first, last) = (self.first_press?, self.last_press?);
+        let span = last.duration_since(first);
+        if self.presses < WPM_MIN_PRESSES || span < WPM_MIN_SPAN {
+            return None;
+        }
+        // n presses are separated by n - 1 gaps over the span
+        let words = (self.presses - 1) as f64 / 5.0;
+        Some(words * 60.0 / span.as_secs_f64())
+    }
+
+    /// Key presses per mouse action, counting clicks, scroll notches and every
+    /// [`PIXELS_PER_MOUSE_ACTION`] pixels of movement. `None` without any mouse
+    /// actions, where the ratio would be infinite or undefined.
+    pub fn keyboard_mouse_ratio(&self) -> Option<f64> {
+        let mouse_actions = self.clicks as f64
+            + self.scroll_x
+            + self.scroll_y
+            + self.distance / PIXELS_PER_MOUSE_ACTION;
+        (mouse_actions > 0.0).then(|| self.presses as f64 / mouse_actions)
+    }
+
+    /// Average cursor speed while moving in pixels per second, pauses between
+    /// movements left out. `None` when no speed was measured.
+    pub fn mouse_speed_avg(&self) -> Option<f64> {
+        (!self.speed_time.is_zero()).then(|| self.speed_distance / self.speed_time.as_secs_f64())
+    }
+
+    /// Whether no input at all was recorded
+    pub fn is_empty(&self) -> bool {
+        !self.active
+            && self.presses == 0
+            && self.clicks == 0
+            && self.delta_x == 0
+            && self.delta_y == 0
+            && self.scroll_x == 0.0
+            && self.scroll_y == 0.0
+    }
+
+    /// Discard the accumulated counters and restart the activity clock, for
+    /// when the recorded input no longer belongs to the current interval
+    pub fn reset(&mut self) {
+        *self = InputState::new(self.options);
+    }
+
+    /// Count a key press, both in total and per category. Only the category is
+    /// looked at, the key itself is never stored.
+    fn record_key(&mut self, key: Key) {
+        self.presses = self.presses.saturating_add(1);
+        match key {
+            Key::Backspace | Key::Delete => self.corrections = self.corrections.saturating_add(1),
+            Key::Return | Key::KpReturn => self.submits = self.submits.saturating_add(1),
+            Key::ControlLeft | Key::ControlRight => {
+                self.ctrl_presses = self.ctrl_presses.saturating_add(1)
+            }
+            Key::Alt | Key::AltGr => self.alt_presses = self.alt_presses.saturating_add(1),
+            Key::ShiftLeft | Key::ShiftRight => {
+                self.shift_presses = self.shift_presses.saturating_add(1)
+            }
+            Key::MetaLeft | Key::MetaRight => {
+                self.meta_presses = self.meta_presses.saturating_add(1)
+            }
+            // Auto-repeat of a held lock key doesn't toggle it again
+            Key::CapsLock | Key::NumLock | Key::ScrollLock
+                if !self.held_keys.contains_key(&key) =>
+            {
+                self.lock_toggles = self.lock_toggles.saturating_add(1)
+            }
+            _ => {}
+        }
+    }
+
+    /// Count a mouse click, both in total and per button, and as a double-click
+    /// when it quickly follows a click of the same button
+    fn record_click(&mut self, button: Button, now: Instant) {
+        self.clicks = self.clicks.saturating_add(1);
+        match button {
+            Button::Left => self.clicks_left = self.clicks_left.saturating_add(1),
+            Button::Right => self.clicks_right = self.clicks_right.saturating_add(1),
+            Button::Middle => self.clicks_middle = self.clicks_middle.saturating_add(1),
+            Button::Unknown(_) => self.clicks_other = self.clicks_other.saturating_add(1),
+        }
+
+        // A completed double-click doesn't start another one, so a triple-click
+        // counts once rather than twice
+        self.last_click = match self.last_click {
+            Some((previous, at))
+                if previous == button
+                    && now.saturating_duration_since(at) <= self.options.double_click_window =>
+            {
+                self.double_clicks = self.double_clicks.saturating_add(1);
+                None
+            }
+            _ => Some((button, now)),
+        };
+    }
+
+    /// Accumulate the pixel distance travelled since the previous mouse move
+    fn record_mouse_move(&mut self, x: f64, y: f64, now: Instant) {
+        // The first move only seeds the position, otherwise the jump from
+        // wherever the cursor was before would be counted as movement
+        if let Some((prev_x, prev_y)) = self.last_position {
+            let dx = (x - prev_x).abs();
+            let dy = (y - prev_y).abs();
+            let distance = (dx * dx + dy * dy).sqrt();
+
+            // A jump no hand could make within one event is the cursor being
+            // warped, so it only moves the position like the first move does
+            if self
+                .options
+                .max_mouse_jump
+                .is_some_and(|max| distance > max)
+            {
+                debug!(
+                    "Ignoring mouse jump of {:.0} pixels from ({}, {}) to ({}, {})",
+                    distance, prev_x, prev_y, x, y
+                );
+                self.last_position = Some((x, y));
+                self.speed_sample = None;
+                return;
+            }
+
+            // Counters saturate instead of wrapping, should a runaway device
+            // spam events. Float sums can't wrap, they only lose precision.
+            self.delta_x = self.delta_x.saturating_add(dx.round() as u64);
+            self.delta_y = self.delta_y.saturating_add(dy.round() as u64);
+            self.distance += distance;
+            if let Some((_, moved)) = &mut self.drag {
+                *moved += distance;
+            }
+            self.record_speed(distance, now);
+        } else {
+            self.speed_sample = Some((now, 0.0));
+        }
+        self.last_position = Some((x, y));
+    }
+
+    /// Measure the speed of the movement since the previous sample, once
+    /// enough time has passed for a meaningful figure
+    fn record_speed(&mut self, distance: f64, now: Instant) {
+        let Some((started, moved)) = self.speed_sample else {
+            self.speed_sample = Some((now, 0.0));
+            return;
+        };
+        let span = now.saturating_duration_since(started);
+        if span > SPEED_MAX_GAP {
+            self.speed_sample = Some((now, 0.0));
+            return;
+        }
+        let moved = moved + distance;
+        if span < SPEED_MIN_SPAN {
+            self.speed_sample = Some((started, moved));
+            return;
+        }
+
+        let speed = moved / span.as_secs_f64();
+        self.mouse_speed_min = Some(self.mouse_speed_min.map_or(speed, |min| min.min(speed)));
+        self.mouse_speed_max = Some(self.mouse_speed_max.map_or(speed, |max| max.max(speed)));
+        self.speed_distance += moved;
+        self.speed_time = self.speed_time.saturating_add(span);
+        self.speed_sample = Some((now, 0.0));
+    }
+
+    /// Count how long a button was held, and complete a drag when the button
+    /// that started it is released
+    fn record_release(&mut self, button: Button, now: Instant) {
+        if let Some(pressed) = self.held_buttons.remove(&button) {
+            self.button_hold = self
+                .button_hold
+                .saturating_add(now.saturating_duration_since(pressed));
+        }
+
+        if let Some((held, moved)) = self.drag {
+            if held == button {
+                if moved >= DRAG_MIN_DISTANCE {
+                    self.drags = self.drags.saturating_add(1);
+                    self.drag_distance += moved;
+                }
+                self.drag = None;
+            }
+        }
+    }
+
+    /// Accumulate scroll distance, both in total and per direction
+    fn record_wheel(&mut self, delta_x: i64, delta_y: i64) {
+        let scale = self.options.scroll_scale;
+        let (dx, dy) = (
+            delta_x.unsigned_abs() as f64 * scale,
+            delta_y.unsigned_abs() as f64 * scale,
+        );
+        self.scroll_x += dx;
+        self.scroll_y += dy;
+
+        // rdev reports positive vertical deltas for scrolling up and
+        // positive horizontal deltas for scrolling right
+        if delta_y > 0 {
+            self.scroll_up += dy;
+        } else {
+            self.scroll_down += dy;
+        }
+        if delta_x > 0 {
+            self.scroll_right += dx;
+        } else {
+            self.scroll_left += dx;
+        }
+    }
+}
+
+/// Input categories that are recorded, disabled ones are ignored by the listener
+/// and left out of the event data
+#[derive(Debug, Clone, Copy)]
+pub struct Tracking {
+    pub keyboard: bool,
+    pub mouse_buttons: bool,
+    pub mouse_move: bool,
+    pub scroll: bool,
+    /// Only record that input happened, see [`InputState::record_activity`]
+    pub activity_only: bool,
+}
+
+impl Default for Tracking {
+    fn default() -> Self {
+        Self {
+            keyboard: true,
+            mouse_buttons: true,
+            mouse_move: true,
+            scroll: true,
+            activity_only: false,
+        }
+    }
+}
+
+impl Tracking {
+    /// Whether an event belongs to a category that is being recorded
+    pub fn tracks(&self, event_type: &EventType) -> bool {
+        match event_type {
+            EventType::KeyPress(_) | EventType::KeyRelease(_) => self.keyboard,
+            EventType::ButtonPress(_) | EventType::ButtonRelease(_) => self.mouse_buttons,
+            EventType::MouseMove { .. } => self.mouse_move,
+            EventType::Wheel { .. } => self.scroll,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[test]
+    fn take_does_not_lose_events_recorded_concurrently() {
+        const EVENTS: u64 = 100_000;
+        let state = Arc::new(Mutex::new(InputState::default()));
+
+        let writer_state = Arc::clone(&state);
+        let writer = thread::spawn(move || {
+            for _ in 0..EVENTS {
+                writer_state.lock().unwrap().presses += 1;
+            }
+        });
+
+        let mut total = 0;
+        while !writer.is_finished() {
+            total += state.lock().unwrap().take().presses;
+        }
+        writer.join().unwrap();
+        total += state.lock().unwrap().take().presses;
+
+        assert_eq!(total, EVENTS);
+    }
+
+    #[test]
+    fn take_keeps_last_activity() {
+        let mut state = InputState {
+            presses: 3,
+            ..Default::default()
+        };
+        state.record_mouse_move(10.0, 10.0, Instant::now());
+        let last_activity = state.last_activity;
+
+        let data = state.take();
+
+        assert_eq!(data.presses, 3);
+        assert_eq!(state.presses, 0);
+        assert_eq!(state.last_position, None);
+        assert_eq!(state.last_activity, last_activity);
+    }
+
+    #[test]
+    fn record_counts_key_presses_and_clicks() {
+        let mut state = InputState::default();
+        for event_type in [
+            EventType::KeyPress(Key::KeyA),
+            EventType::KeyRelease(Key::KeyA),
+            EventType::KeyPress(Key::Space),
+            EventType::KeyPress(Key::Backspace),
+            EventType::KeyPress(Key::Delete),
+            EventType::KeyPress(Key::Return),
+            EventType::KeyPress(Key::KpReturn),
+            EventType::KeyPress(Key::ControlLeft),
+            EventType::KeyPress(Key::ControlRight),
+            EventType::KeyPress(Key::AltGr),
+            EventType::KeyPress(Key::ShiftLeft),
+            EventType::KeyPress(Key::MetaRight),
+            EventType::KeyPress(Key::CapsLock),
+            EventType::KeyPress(Key::CapsLock),
+            EventType::KeyRelease(Key::CapsLock),
+            EventType::KeyPress(Key::NumLock),
+            EventType::ButtonPress(Button::Left),
+            EventType::ButtonRelease(Button::Left),
+            EventType::ButtonPress(Button::Right),
+            EventType::ButtonPress(Button::Middle),
+            EventType::ButtonPress(Button::Unknown(4)),
+        ] {
+            state.record(event_type);
+        }
+
+        assert_eq!(state.presses, 14);
+        assert_eq!(state.corrections, 2);
+        assert_eq!(state.submits, 2);
+        assert_eq!(state.ctrl_presses, 2);
+        assert_eq!(state.alt_presses, 1);
+        assert_eq!(state.shift_presses, 1);
+        assert_eq!(state.meta_presses, 1);
+        assert_eq!(state.lock_toggles, 2);
+        assert_eq!(state.clicks, 4);
+        assert_eq!(state.clicks_left, 1);
+        assert_eq!(state.clicks_right, 1);
+        assert_eq!(state.clicks_middle, 1);
+        assert_eq!(state.clicks_other, 1);
+    }
+
+    #[test]
+    fn record_counts_double_clicks() {
+        let mut state = InputState::default();
+        let origin = state.slice_origin;
+        for (button, millis) in [
+            // A double-click followed by a third quick click
+            (Button::Left, 0),
+            (Button::Left, 200),
+            (Button::Left, 400),
+            // Too slow, and a different button in between
+            (Button::Left, 1000),
+            (Button::Left, 1500),
+            (Button::Right, 1600),
+            (Button::Left, 1700),
+        ] {
+            state.record_at(
+                EventType::ButtonPress(button),
+                origin + Duration::from_millis(millis),
+            );
+        }
+
+        assert_eq!(state.clicks, 7);
+        assert_eq!(state.double_clicks, 1);
+
+        // The first click of a double-click may land in the previous interval
+        state.take();
+        state.record_at(
+            EventType::ButtonPress(Button::Left),
+            origin + Duration::from_millis(1900),
+        );
+        assert_eq!(state.double_clicks, 1);
+    }
+
+    #[test]
+    fn record_counts_drags() {
+        let mut state = InputState::default();
+        for event_type in [
+            // A click with a slight wobble isn't a drag
+            EventType::MouseMove { x: 0.0, y: 0.0 },
+            EventType::ButtonPress(Button::Left),
+            EventType::MouseMove { x: 1.0, y: 0.0 },
+            EventType::ButtonRelease(Button::Left),
+            // Another button released during the drag doesn't end it
+            EventType::ButtonPress(Button::Left),
+            EventType::MouseMove { x: 4.0, y: 4.0 },
+            EventType::ButtonPress(Button::Right),
+            EventType::ButtonRelease(Button::Right),
+            EventType::MouseMove { x: 4.0, y: 10.0 },
+        ] {
+            state.record(event_type);
+        }
+        assert_eq!(state.drags, 0);
+
+        // The drag carries over into the next interval
+        state.take();
+        state.record(EventType::ButtonRelease(Button::Left));
+        assert_eq!(state.drags, 1);
+        assert_eq!(state.drag_distance, 11.0);
+    }
+
+    #[test]
+    fn record_measures_mouse_speed() {
+        let mut state = InputState::default();
+        let origin = state.slice_origin;
+        let at = |millis| origin + Duration::from_millis(millis);
+        for (x, millis) in [
+            // The first move only seeds the measurement
+            (0.0, 0),
+            (10.0, 100),
+            // Moves at the same instant are measured together with the next one
+            (20.0, 100),
+            (40.0, 200),
+            // A move after a pause starts over instead of measuring the pause
+            (50.0, 5000),
+            (60.0, 5500),
+        ] {
+            state.record_at(EventType::MouseMove { x, y: 0.0 }, at(millis));
+        }
+
+        assert_eq!(state.distance, 60.0);
+        assert_eq!(state.mouse_speed_min, Some(20.0));
+        assert_eq!(state.mouse_speed_max, Some(300.0));
+        // 50 pixels over 0.7 seconds of movement
+        let average = state.mouse_speed_avg().unwrap();
+        assert!((average - 50.0 / 0.7).abs() < 1e-9);
+
+        let data = state.take();
+        assert_eq!(data.mouse_speed_max, Some(300.0));
+        assert_eq!(state.mouse_speed_max, None);
+        assert_eq!(state.mouse_speed_avg(), None);
+    }
+
+    #[test]
+    fn record_counts_bursts_after_pauses() {
+        let mut state = InputState::default();
+        let origin = state.last_activity;
+        let at = |millis| origin + Duration::from_millis(millis);
+        for millis in [3000, 4000, 5500, 9000, 9100, 12000] {
+            state.record_at(EventType::KeyPress(Key::KeyA), at(millis));
+            state.record_at(EventType::KeyRelease(Key::KeyA), at(millis));
+        }
+        // Releases don't count as activity, so they neither start nor extend a burst
+        assert_eq!(state.bursts, 3);
+
+        // A burst going on at the interval boundary isn't counted again
+        state.take();
+        state.record_at(EventType::ButtonPress(Button::Left), at(13000));
+        assert_eq!(state.bursts, 0);
+        state.record_at(EventType::ButtonPress(Button::Left), at(16000));
+        assert_eq!(state.bursts, 1);
+
+        // A pause spanning the interval boundary ends the burst
+        state.take();
+        state.record_at(EventType::ButtonPress(Button::Left), at(20000));
+        assert_eq!(state.bursts, 1);
+    }
+
+    #[test]
+    fn record_activity_ends_holds() {
+        let mut state = InputState::default();
+        state.record(EventType::KeyPress(Key::CapsLock));
+        state.record(EventType::ButtonPress(Button::Left));
+        // Tracking paused while both were held down
+        state.record_activity(EventType::KeyRelease(Key::CapsLock));
+        state.record_activity(EventType::ButtonRelease(Button::Left));
+        assert!(state.held_keys.is_empty());
+        assert!(state.held_buttons.is_empty());
+        assert_eq!(state.drag, None);
+
+        // Pressing the key after resuming toggles the lock, it isn't taken for
+        // auto-repeat of a key that is still held
+        state.take();
+        state.record(EventType::KeyPress(Key::CapsLock));
+        assert_eq!(state.lock_toggles, 1);
+    }
+
+    #[test]
+    fn record_counts_the_first_burst_right_after_startup() {
+        let mut state = InputState::default();
+        let now = state.last_activity;
+        state.record_at(EventType::KeyPress(Key::KeyA), now);
+        assert_eq!(state.bursts, 1);
+        state.record_at(
+            EventType::KeyPress(Key::KeyA),
+            now + Duration::from_millis(100),
+        );
+        assert_eq!(state.bursts, 1);
+    }
+
+    #[test]
+    fn record_counts_hold_durations() {
+        let mut state = InputState::default();
+        let origin = state.slice_origin;
+        let at = |millis| origin + Duration::from_millis(millis);
+        for (event_type, millis) in [
+            // Auto-repeat doesn't restart the hold
+            (EventType::KeyPress(Key::KeyA), 0),
+            (EventType::KeyPress(Key::KeyA), 50),
+            (EventType::KeyRelease(Key::KeyA), 100),
+            (EventType::ButtonPress(Button::Left), 200),
+            (EventType::ButtonRelease(Button::Left), 500),
+            // Releases without a recorded press are ignored
+            (EventType::KeyRelease(Key::KeyB), 600),
+            (EventType::KeyPress(Key::ShiftLeft), 700),
+        ] {
+            state.record_at(event_type, at(millis));
+        }
+
+        let data = state.take();
+        assert_eq!(data.key_hold, Duration::from_millis(100));
+        assert_eq!(data.button_hold, Duration::from_millis(300));
+
+        // A key held across the boundary counts in full where it's released
+        state.record_at(EventType::KeyRelease(Key::ShiftLeft), at(1000));
+        assert_eq!(state.key_hold, Duration::from_millis(300));
+    }
+
+    #[test]
+    fn counters_saturate_instead_of_wrapping() {
+        let mut state = InputState {
+            presses: u64::MAX,
+            clicks: u64::MAX,
+            active_seconds: u64::MAX,
+            key_hold: Duration::MAX,
+            ..Default::default()
+        };
+        let origin = state.slice_origin;
+        state.record_at(EventType::KeyPress(Key::KeyA), origin);
+        state.record_at(
+            EventType::KeyRelease(Key::KeyA),
+            origin + Duration::from_millis(100),
+        );
+        state.record_at(EventType::ButtonPress(Button::Left), origin);
+
+        assert_eq!(state.presses, u64::MAX);
+        assert_eq!(state.clicks, u64::MAX);
+        assert_eq!(state.active_seconds, u64::MAX);
+        assert_eq!(state.key_hold, Duration::MAX);
+        assert_eq!(state.clicks_left, 1);
+    }
+
+    #[test]
+    fn record_accumulates_mouse_movement() {
+        let mut state = InputState::default();
+        for (x, y) in [(100.0, 100.0), (103.0, 104.0), (100.0, 100.0)] {
+            state.record(EventType::MouseMove { x, y });
+        }
+
+        // The first move only seeds the position
+        assert_eq!(state.delta_x, 6);
+        assert_eq!(state.delta_y, 8);
+        assert_eq!(state.distance, 10.0);
+    }
+
+    #[test]
+    fn record_ignores_mouse_jumps() {
+        let mut state = InputState::new(InputOptions {
+            max_mouse_jump: Some(100.0),
+            ..Default::default()
+        });
+        for (x, y) in [(0.0, 0.0), (30.0, 40.0), (3000.0, 40.0), (3000.0, 100.0)] {
+            state.record(EventType::MouseMove { x, y });
+        }
+
+        // Movement continues from where the cursor landed
+        assert_eq!(state.delta_x, 30);
+        assert_eq!(state.delta_y, 100);
+        assert_eq!(state.distance, 110.0);
+
+        state.options.max_mouse_jump = None;
+        state.record(EventType::MouseMove { x: 0.0, y: 100.0 });
+        assert_eq!(state.delta_x, 3030);
+    }
+
+    #[test]
+    fn record_uses_absolute_wheel_deltas() {
+        let mut state = InputState::new(InputOptions {
+            scroll_scale: 1.0,
+            ..Default::default()
+        });
+        for (delta_x, delta_y) in [(0, 3), (0, -2), (-4, 0), (1, -1)] {
+            state.record(EventType::Wheel { delta_x, delta_y });
+        }
+
+        assert_eq!(state.scroll_x, 5.0);
+        assert_eq!(state.scroll_y, 6.0);
+        assert_eq!(state.scroll_up, 3.0);
+        assert_eq!(state.scroll_down, 3.0);
+        assert_eq!(state.scroll_left, 4.0);
+        assert_eq!(state.scroll_right, 1.0);
+    }
+
+    #[test]
+    fn record_scales_wheel_deltas() {
+        let mut state = InputState::new(InputOptions {
+            scroll_scale: 0.25,
+            ..Default::default()
+        });
+        for delta_y in [-120, -120, 60] {
+            state.record(EventType::Wheel {
+                delta_x: 0,
+                delta_y,
+            });
+        }
+
+        assert_eq!(state.scroll_y, 75.0);
+        assert_eq!(state.scroll_down, 60.0);
+        assert_eq!(state.scroll_up, 15.0);
+    }
+
+    #[test]
+    fn record_ignores_releases_for_last_activity() {
+        let mut state = InputState::default();
+        let last_activity = state.last_activity;
+        thread::sleep(std::time::Duration::from_millis(5));
+
+        state.record(EventType::KeyRelease(Key::KeyA));
+        state.record(EventType::ButtonRelease(Button::Left));
+        assert_eq!(state.last_activity, last_activity);
+
+        state.record(EventType::KeyPress(Key::KeyA));
+        assert!(state.last_activity > last_activity);
+    }
+
+    #[test]
+    fn record_tracks_keyboard_and_mouse_activity_separately() {
+        let mut state = InputState::default();
+        let origin = state.slice_origin;
+        let key_time = origin + Duration::from_millis(100);
+        let mouse_time = origin + Duration::from_millis(200);
+
+        state.record_at(EventType::KeyPress(Key::KeyA), key_time);
+        state.record_at(
+            EventType::Wheel {
+                delta_x: 0,
+                delta_y: 1,
+            },
+            mouse_time,
+        );
+
+        assert_eq!(state.last_keyboard_activity, key_time);
+        assert_eq!(state.last_mouse_activity, mouse_time);
+        assert_eq!(state.last_activity, mouse_time);
+
+        state.take();
+        assert_eq!(state.last_keyboard_activity, key_time);
+        assert_eq!(state.last_mouse_activity, mouse_time);
+    }
+
+    #[test]
+    fn wpm_needs_enough_presses() {
+        // Four quick presses are too few to extrapolate from
+        let mut state = InputState::default();
+        let origin = state.slice_origin;
+        for n in 0..4 {
+            state.record_at(
+                EventType::KeyPress(Key::KeyA),
+                origin + Duration::from_millis(n * 100),
+            );
+        }
+        assert_eq!(state.wpm(), None);
+
+        // 11 presses over two seconds are 10 gaps, two words per two seconds
+        let mut state = InputState::default();
+        let origin = state.slice_origin;
+        for n in 0..=10 {
+            state.record_at(
+                EventType::KeyPress(Key::KeyA),
+                origin + Duration::from_millis(n * 200),
+            );
+        }
+        assert_eq!(state.wpm(), Some(60.0));
+    }
+
+    #[test]
+    fn keyboard_mouse_ratio_weighs_clicks_scrolls_and_movement() {
+        let mut state = InputState {
+            presses: 30,
+            ..Default::default()
+        };
+        assert_eq!(state.keyboard_mouse_ratio(), None);
+
+        state.clicks = 5;
+        state.scroll_y = 3.0;
+        state.distance = 200.0;
+        assert_eq!(state.keyboard_mouse_ratio(), Some(3.0));
+
+        state.presses = 0;
+        assert_eq!(state.keyboard_mouse_ratio(), Some(0.0));
+    }
+
+    #[test]
+    fn record_counts_active_slices() {
+        let mut state = InputState::default();
+        let origin = state.slice_origin;
+        for millis in [100, 200, 900, 1500, 3100, 3200] {
+            state.record_at(
+                EventType::KeyPress(Key::KeyA),
+                origin + Duration::from_millis(millis),
+            );
+        }
+
+        assert_eq!(state.presses, 6);
+        assert_eq!(state.active_seconds, 3);
+
+        let data = state.take();
+        assert_eq!(data.active_seconds, 3);
+        assert_eq!(state.active_seconds, 0);
+        assert_eq!(state.last_slice, None);
+    }
+
+    #[test]
+    fn record_activity_keeps_no_counts() {
+        let mut state = InputState::default();
+        state.record_activity(EventType::KeyPress(Key::KeyA));
+        state.record_activity(EventType::MouseMove { x: 0.0, y: 0.0 });
+        state.record_activity(EventType::MouseMove { x: 5.0, y: 5.0 });
+
+        assert!(state.active);
+        assert!(!state.is_empty());
+        assert_eq!(state.presses, 0);
+        assert_eq!(state.delta_x, 0);
+        assert_eq!(state.active_seconds, 0);
+
+        state.take();
+        assert!(state.is_empty());
+    }
+
+    #[test]
+    fn take_zeroes_every_counter() {
+        let mut state = InputState::default();
+        for event_type in [
+            EventType::KeyPress(Key::KeyA),
+            EventType::ButtonPress(Button::Left),
+            EventType::MouseMove { x: 0.0, y: 0.0 },
+            EventType::MouseMove { x: 5.0, y: 5.0 },
+            EventType::Wheel {
+                delta_x: 1,
+                delta_y: -1,
+            },
+        ] {
+            state.record(event_type);
+        }
+        let last_activity = state.last_activity;
+
+        let data = state.take();
+        assert_eq!(data.presses, 1);
+        assert_eq!(data.clicks, 1);
+        assert_eq!(data.delta_x, 5);
+        assert_eq!(data.scroll_y, 1.0 * DEFAULT_SCROLL_SCALE);
+
+        assert_eq!(state.presses, 0);
+        assert_eq!(state.clicks, 0);
+        assert_eq!(state.delta_x, 0);
+        assert_eq!(state.delta_y, 0);
+        assert_eq!(state.scroll_x, 0.0);
+        assert_eq!(state.scroll_y, 0.0);
+        assert_eq!(state.distance, 0.0);
+        assert_eq!(state.last_activity, last_activity);
+
+        // Movement after a reset starts from a fresh position
+        state.record(EventType::MouseMove { x: 50.0, y: 50.0 });
+        assert_eq!(state.delta_x, 0);
+    }
+}
@@ -0,0 +1,77 @@
+//! Input tracking behind aw-watcher-input-rs.
+//!
+//! [`InputState`] accumulates keyboard and mouse activity from rdev events, and
+//! [`create_input_listener_thread`] feeds it from the system input hooks. The
+//! binary wires both to aw-server, but they don't depend on it.
+//!
+//! With the `mock_input` feature the listener replays a scripted event stream
+//! instead, so the whole pipeline can be tested without input devices.
+
+use std::sync::atomic::AtomicBool;
+
+mod input;
+#[cfg(not(feature = "mock_input"))]
+mod listener;
+#[cfg(feature = "mock_input")]
+mod mock;
+mod permissions;
+
+pub use input::{InputOptions, InputState, Tracking};
+#[cfg(not(feature = "mock_input"))]
+pub use listener::create_input_listener_thread;
+#[cfg(feature = "mock_input")]
+pub use mock::{create_input_listener_thread, MOCK_EVENTS_ENV};
+pub use permissions::{input_monitoring_allowed, request_input_monitoring};
+
+/// How input is captured on Linux
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CaptureMode {
+    /// rdev's listen mode, through the display server
+    #[default]
+    Listen,
+    /// rdev's grab mode, reading the input devices directly. Needs the
+    /// `unstable_grab` feature
+    Grab,
+}
+
+impl CaptureMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CaptureMode::Listen => "listen",
+            CaptureMode::Grab => "grab",
+        }
+    }
+}
+
+/// How the listener thread captures input and recovers from errors
+#[derive(Debug, Clone, Copy)]
+pub struct ListenerOptions {
+    /// Whether to listen or grab, grabbing is only possible with `unstable_grab` on Linux
+    pub capture_mode: CaptureMode,
+    /// Number of times input capture is restarted after an error before giving up
+    pub retries: u32,
+    /// In grab mode, switch to listen mode when the input devices can't be opened
+    pub grab_fallback: bool,
+}
+
+impl Default for ListenerOptions {
+    fn default() -> Self {
+        Self {
+            capture_mode: CaptureMode::Listen,
+            retries: 5,
+            grab_fallback: true,
+        }
+    }
+}
+
+/// Global atomic for signaling threads to stop, the listener stops recording
+/// events once it is cleared
+pub static RUNNING: AtomicBool = AtomicBool::new(true);
+
+/// Set while tracking is paused by quiet hours or a signal, the listener then
+/// only notes that there was input, without counting it
+pub static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Set once the listener fell back from grab to listen mode, so the capture
+/// mode reported with each heartbeat is the one actually in use
+pub static GRAB_FELL_BACK: AtomicBool = AtomicBool::new(false);
@@ -0,0 +1,179 @@
+use crate::input::{InputState, Tracking};
+#[cfg(all(target_os = "linux", feature = "unstable_grab"))]
+use crate::CaptureMode;
+#[cfg(all(target_os = "linux", feature = "unstable_grab"))]
+use crate::GRAB_FELL_BACK;
+use crate::{ListenerOptions, PAUSED, RUNNING};
+use log::warn;
+// The grab function is compiled in on Linux when the unstable_grab feature is
+// enabled, and used with --capture-mode grab. It intercepts all input events
+// before they are delivered to applications
+#[cfg(all(target_os = "linux", feature = "unstable_grab"))]
+use rdev::{grab, GrabError};
+// The standard listen function is used on all other platforms, and as the
+// fallback when grabbing isn't permitted
+use rdev::{listen, Event as RdevEvent, ListenError};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Start a thread that records input events into `state` until the process exits.
+///
+/// When capturing input fails it is restarted up to `options.retries` times
+/// with a growing delay. The thread only returns once input can't be captured
+/// any more, with a description of the last error.
+pub fn create_input_listener_thread(
+    state: Arc<Mutex<InputState>>,
+    tracking: Tracking,
+    options: ListenerOptions,
+) -> JoinHandle<Result<(), String>> {
+    thread::spawn(move || {
+        let retries = options.retries;
+        let mut attempt = 0;
+        #[cfg(all(target_os = "linux", feature = "unstable_grab"))]
+        let mut grab_mode = options.capture_mode == CaptureMode::Grab;
+        loop {
+            #[cfg(all(target_os = "linux", feature = "unstable_grab"))]
+            let (message, permanent) = if grab_mode {
+                run_grab(Arc::clone(&state), tracking)
+            } else {
+                run_listen(Arc::clone(&state), tracking)
+            };
+            #[cfg(not(all(target_os = "linux", feature = "unstable_grab")))]
+            let (message, permanent) = run_listen(Arc::clone(&state), tracking);
+
+            // Listening often works without access to the input devices, so
+            // keep collecting data until the permissions are sorted out
+            #[cfg(all(target_os = "linux", feature = "unstable_grab"))]
+            if permanent && grab_mode && options.grab_fallback {
+                warn!("{}, falling back to listen mode", message);
+                grab_mode = false;
+                GRAB_FELL_BACK.store(true, Ordering::SeqCst);
+                continue;
+            }
+
+            if permanent || attempt >= retries {
+                return Err(message);
+            }
+
+            attempt += 1;
+            let delay = Duration::from_secs(1 << (attempt - 1).min(5));
+            warn!(
+                "{}, restarting input capture in {:?} (attempt {}/{})",
+                message, delay, attempt, retries
+            );
+            thread::sleep(delay);
+            if !RUNNING.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+        }
+    })
+}
+
+/// Capture input until rdev stops, returning the reason and whether it is
+/// permanent so that retrying won't help.
+///
+/// Standard input listening mode on all platforms, and the fallback when
+/// grabbing isn't permitted.
+fn run_listen(state: Arc<Mutex<InputState>>, tracking: Tracking) -> (String, bool) {
+    let callback = move |event: RdevEvent| {
+        // Skip disabled categories first, a fast mouse sends moves at up to
+        // 1000 Hz and they then cost neither the atomic load nor the lock
+        if !tracking.tracks(&event.event_type) {
+            return;
+        }
+
+        // Stop recording once shutdown has started, the main loop takes
+        // the final snapshot and the process exits when it returns
+        if !RUNNING.load(Ordering::SeqCst) {
+            return;
+        }
+
+        // Lock the state to update
+        if let Ok(mut state_guard) = state.lock() {
+            if tracking.activity_only || PAUSED.load(Ordering::Relaxed) {
+                state_guard.record_activity(event.event_type);
+            } else {
+                state_guard.record(event.event_type);
+            }
+        }
+    };
+
+    // Start listening for input events
+    // Note: This is a blocking call that only returns when listening fails
+    match listen(callback) {
+        Ok(()) => ("Input listener stopped".to_string(), false),
+        Err(error) => {
+            // A missing event tap means no accessibility permission on macOS,
+            // and X servers without the RECORD extension never gain it
+            let permanent = matches!(
+                error,
+                ListenError::EventTapError | ListenError::XRecordExtensionError
+            );
+            (
+                format!("Error listening for input events: {:?}", error),
+                permanent,
+            )
+        }
+    }
+}
+
+/// Capture input until rdev stops, returning the reason and whether it is
+/// permanent so that retrying won't help.
+///
+/// Grab mode on Linux, which intercepts events before they reach applications.
+#[cfg(all(target_os = "linux", feature = "unstable_grab"))]
+fn run_grab(state: Arc<Mutex<InputState>>, tracking: Tracking) -> (String, bool) {
+    use log::error;
+
+    let callback = move |event: RdevEvent| -> Option<RdevEvent> {
+        // Skip disabled categories first, so they cost neither the atomic
+        // load nor the lock
+        if !tracking.tracks(&event.event_type) {
+            return Some(event);
+        }
+
+        // Stop recording once shutdown has started, but keep passing
+        // events through until the process exits
+        if !RUNNING.load(Ordering::SeqCst) {
+            return Some(event);
+        }
+
+        // Lock the state to update
+        if let Ok(mut state_guard) = state.lock() {
+            if tracking.activity_only || PAUSED.load(Ordering::Relaxed) {
+                state_guard.record_activity(event.event_type);
+            } else {
+                state_guard.record(event.event_type);
+            }
+        }
+
+        // Return the event to pass it through without modification
+        Some(event)
+    };
+
+    // Start grabbing input events
+    // Note: This is a blocking call that only returns when grabbing fails
+    match grab(callback) {
+        Ok(()) => ("Input grab stopped".to_string(), false),
+        Err(error) => {
+            // Input devices that can't be opened stay that way until the
+            // user's groups change
+            let permanent = match &error {
+                GrabError::IoError(e) => e.kind() == std::io::ErrorKind::PermissionDenied,
+                GrabError::EventTapError => true,
+                _ => false,
+            };
+            if permanent {
+                error!("Note: On Linux, this program must be run as root or by a user in the 'input' group");
+                error!("To add your user to the input group: sudo usermod -a -G input $USER");
+                error!("You may need to log out and back in for the changes to take effect");
+            }
+            (
+                format!("Error grabbing input events: {:?}", error),
+                permanent,
+            )
+        }
+    }
+}
@@ -1,418 +1,3389 @@
+// A windows_gui build is a GUI application, which Windows starts without a console
+#![cfg_attr(all(windows, feature = "windows_gui"), windows_subsystem = "windows")]
+
 use aw_client_rust::blocking::AwClient;
-use aw_models::Event;
-use chrono::{TimeDelta, Utc};
-use clap::Parser;
-use config::{Config, ConfigError, File};
-use dirs::config_dir;
+use aw_models::{Bucket, BucketMetadata, Event};
+use aw_watcher_input_rs::{
+    create_input_listener_thread, input_monitoring_allowed, request_input_monitoring, CaptureMode,
+    InputOptions, InputState, ListenerOptions, Tracking, GRAB_FELL_BACK, PAUSED, RUNNING,
+};
+use chrono::{Local, NaiveDate, TimeDelta, Timelike, Utc};
+use clap::{Parser, ValueEnum};
+use config::{Config, ConfigError, Environment, File};
+use dirs::{cache_dir, config_dir, runtime_dir};
 use hostname::get as get_hostname;
-// Use the grab function on Linux when the unstable_grab feature is enabled
-// This allows intercepting all input events before they are delivered to applications
-#[cfg(all(target_os = "linux", feature = "unstable_grab"))]
-use rdev::{grab, Event as RdevEvent, EventType};
-// Use the standard listen function on all other platforms
-#[cfg(not(all(target_os = "linux", feature = "unstable_grab")))]
-use rdev::{listen, Event as RdevEvent, EventType};
+use log::{debug, error, info, warn};
+use sender::{spawn_sender, Outgoing};
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
-use std::fs::{create_dir_all, write};
+use server::Scheme;
+use sink::{Backlog, EventSink, SenderSettings};
+use std::collections::hash_map::RandomState;
+use std::fs::{create_dir_all, write, OpenOptions, TryLockError};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::thread::{self, sleep, JoinHandle};
+use std::thread::{self, sleep};
 use std::time::{Duration, Instant};
+use totals::{DayTotals, LifetimeTotals};
+use window::RollingWindow;
+
+#[cfg(feature = "buffer_db")]
+mod buffer;
+mod metrics;
+mod power;
+mod queue;
+mod sender;
+mod server;
+mod service;
+mod sink;
+#[cfg(unix)]
+mod status;
+mod systemd;
+mod totals;
+mod window;
 
 /// Configuration structure for aw-watcher-input
 #[derive(Debug, Serialize, Deserialize)]
 struct AppConfig {
-    /// Polling interval in seconds
+    /// Polling interval in seconds, fractions allow sub-second polling
     #[serde(default = "default_polling_interval")]
-    polling_interval: u64,
-}
+    polling_interval: f64,
 
-fn default_polling_interval() -> u64 {
-    1
-}
+    /// Polling interval in seconds used after `idle_after_intervals` intervals
+    /// in a row without input, 0 always polls at `polling_interval`
+    #[serde(default)]
+    idle_polling_interval: f64,
 
-impl AppConfig {
-    fn new() -> Result<Self, ConfigError> {
-        let default_config = Self {
-            polling_interval: default_polling_interval(),
-        };
+    /// Number of intervals in a row without input before switching to
+    /// `idle_polling_interval`
+    #[serde(default = "default_idle_after_intervals")]
+    idle_after_intervals: u32,
 
-        let config_path = if let Some(config_dir) = config_dir() {
-            let aw_config_dir = config_dir.join("activitywatch").join("aw-watcher-input");
+    /// Polling interval in seconds used while running on battery, 0 polls at
+    /// `polling_interval` regardless of the power source
+    #[serde(default)]
+    battery_polling_interval: f64,
 
-            create_dir_all(&aw_config_dir).ok();
+    /// Seconds without input after which the user is considered AFK
+    #[serde(default = "default_afk_threshold")]
+    afk_threshold: u64,
 
-            let config_file = aw_config_dir.join("config.toml");
+    /// Which input keeps the user from being considered AFK
+    #[serde(default)]
+    afk_input: AfkInput,
 
-            if !config_file.exists() {
-                let default_config_str = toml::to_string_pretty(&default_config).unwrap();
-                write(&config_file, default_config_str).ok();
-            }
+    /// Also report AFK status to an aw-watcher-afk compatible bucket
+    #[serde(default)]
+    afk: bool,
 
-            Some(config_file)
-        } else {
-            None
-        };
+    /// Seconds added to the polling interval to get the heartbeat pulsetime
+    #[serde(default = "default_pulsetime_margin")]
+    pulsetime_margin: f64,
 
-        let mut builder = Config::builder();
+    /// Up to this many milliseconds of random delay before each heartbeat, so
+    /// that many watchers started together don't hit the server at once
+    #[serde(default)]
+    jitter_ms: u64,
 
-        if let Some(path) = config_path {
-            if path.exists() {
-                builder = builder.add_source(File::from(path));
-            }
-        }
+    /// Seconds of recent intervals the window_* rates are computed over, 0
+    /// leaves them out
+    #[serde(default)]
+    aggregation_window: f64,
+
+    /// Raise the polling interval when every cycle keeps taking longer than it,
+    /// instead of only suggesting a larger one
+    #[serde(default)]
+    raise_interval_on_overrun: bool,
+
+    /// Number of times a failed heartbeat is retried before giving up
+    #[serde(default = "default_heartbeat_retries")]
+    heartbeat_retries: u32,
+
+    /// Seconds to wait for a connection to the server before counting a
+    /// heartbeat as failed
+    #[serde(default = "default_connect_timeout")]
+    connect_timeout: u64,
+
+    /// Seconds to wait for the server to answer a heartbeat before counting it
+    /// as failed, 0 waits indefinitely
+    #[serde(default = "default_request_timeout")]
+    request_timeout: u64,
+
+    /// Maximum number of events kept in memory while the server is unreachable
+    #[serde(default = "default_max_queued_events")]
+    max_queued_events: usize,
+
+    /// Seconds to wait between attempts to reach the server at startup
+    #[serde(default = "default_startup_retry_interval")]
+    startup_retry_interval: u64,
+
+    /// Number of attempts to reach the server at startup before giving up, 0 retries forever
+    #[serde(default)]
+    startup_retries: u32,
+
+    /// Seconds the wall clock may run ahead of the polling interval before the
+    /// gap is treated as a system sleep, 0 disables the detection
+    #[serde(default = "default_resume_threshold")]
+    resume_threshold: u64,
+
+    /// Number of times input capture is restarted after an error before giving up
+    #[serde(default = "default_listener_retries")]
+    listener_retries: u32,
+
+    /// In grab mode, fall back to listen mode when grabbing isn't permitted
+    #[serde(default = "default_true")]
+    grab_fallback: bool,
+
+    /// Add the capture mode in use to every input event
+    #[serde(default)]
+    report_capture_mode: bool,
+
+    /// Refuse to start while another instance feeds the same bucket
+    #[serde(default = "default_true")]
+    single_instance: bool,
+
+    /// Leave intervals without any input out of the bucket
+    #[serde(default)]
+    skip_empty: bool,
+
+    /// Mark when the watcher starts and stops with events in the input bucket
+    #[serde(default)]
+    session_events: bool,
+
+    /// Send each day's totals to a separate bucket once the local date changes
+    #[serde(default)]
+    daily_summary: bool,
+
+    /// aw-server host, overridden by --host
+    #[serde(default = "default_host")]
+    host: String,
+
+    /// aw-server port, overridden by --port
+    #[serde(default = "default_port")]
+    port: u16,
+
+    /// Whether to connect over HTTP or HTTPS, overridden by --scheme
+    #[serde(default)]
+    scheme: Scheme,
+
+    /// PEM certificate to trust for HTTPS, overridden by --ca-cert
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ca_cert: Option<String>,
+
+    /// Hostname to use in bucket IDs instead of the detected one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hostname: Option<String>,
+
+    /// Bucket ID to use instead of the hostname-derived one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bucket_id: Option<String>,
+
+    /// Client name reported to aw-server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_id: Option<String>,
+
+    /// On Unix, serve the live counters as JSON on a local socket
+    #[serde(default)]
+    status_socket: bool,
+
+    /// Path of the status socket instead of one in the runtime directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    status_socket_path: Option<String>,
+
+    /// Keep all-time totals of presses, clicks and distance in totals.json
+    #[serde(default = "default_true")]
+    lifetime_totals: bool,
+
+    /// Only report whether there was any input, without counting it
+    #[serde(default)]
+    privacy_mode: bool,
+
+    /// Local time ranges such as "22:00-07:00" during which nothing is recorded
+    /// or sent
+    #[serde(default)]
+    quiet_hours: Vec<String>,
+
+    /// Record key presses
+    #[serde(default = "default_true")]
+    track_keyboard: bool,
+
+    /// Record mouse button clicks
+    #[serde(default = "default_true")]
+    track_mouse_buttons: bool,
+
+    /// Record mouse movement
+    #[serde(default = "default_true")]
+    track_mouse_move: bool,
+
+    /// Record scroll wheel activity
+    #[serde(default = "default_true")]
+    track_scroll: bool,
+
+    /// Longest gap in milliseconds between two clicks of the same button that
+    /// counts as a double-click
+    #[serde(default = "default_double_click_ms")]
+    double_click_ms: u64,
+
+    /// Single mouse moves longer than this many pixels are treated as the cursor
+    /// being warped and add no distance, 0 disables the filter
+    #[serde(default = "default_max_mouse_jump")]
+    max_mouse_jump: u64,
+
+    /// Factor applied to the raw scroll deltas so they come out in wheel notches
+    #[serde(default = "default_scroll_scale")]
+    scroll_scale: f64,
+
+    /// Input after a pause longer than this many milliseconds starts a new burst
+    #[serde(default = "default_burst_gap_ms")]
+    burst_gap_ms: u64,
+
+    /// Where heartbeats are sent, every sink gets each heartbeat
+    #[serde(default = "default_sinks")]
+    sinks: Vec<SinkKind>,
+
+    /// File the jsonl sink appends input events to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output_file: Option<String>,
+
+    /// Database the sqlite sink logs input events to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sqlite_file: Option<String>,
+}
+
+/// A destination for heartbeats
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum SinkKind {
+    /// The input and AFK buckets on aw-server
+    AwServer,
+    /// Input events appended to `output_file`, one JSON object per line
+    Jsonl,
+    /// Input events logged to `sqlite_file`, with the buffer_db feature
+    Sqlite,
+    /// Heartbeats printed to stdout
+    Stdout,
+}
 
-        match builder.build()?.try_deserialize() {
-            Ok(config) => Ok(config),
-            Err(_) => Ok(default_config),
+impl SinkKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SinkKind::AwServer => "aw-server",
+            SinkKind::Jsonl => "jsonl",
+            SinkKind::Sqlite => "sqlite",
+            SinkKind::Stdout => "stdout",
         }
     }
 }
 
-#[derive(Debug, Clone)]
-struct InputState {
-    presses: u64,
-    clicks: u64,
-    delta_x: u64,
-    delta_y: u64,
-    scroll_x: u64,
-    scroll_y: u64,
-    last_activity: Instant,
+/// Input that counts as activity for the AFK decision
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AfkInput {
+    /// Any key press, click, mouse move or scroll
+    #[default]
+    Any,
+    /// Only key presses, so that e.g. scrolling through a video counts as AFK
+    Keyboard,
+    /// Only clicks, mouse moves and scrolls
+    Mouse,
 }
 
-impl Default for InputState {
-    fn default() -> Self {
-        Self {
-            presses: 0,
-            clicks: 0,
-            delta_x: 0,
-            delta_y: 0,
-            scroll_x: 0,
-            scroll_y: 0,
-            last_activity: Instant::now(),
+impl AfkInput {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AfkInput::Any => "any",
+            AfkInput::Keyboard => "keyboard",
+            AfkInput::Mouse => "mouse",
         }
     }
 }
 
-// Global atomic for signaling threads to stop
-static RUNNING: AtomicBool = AtomicBool::new(true);
+fn default_polling_interval() -> f64 {
+    1.0
+}
 
-fn create_input_listener_thread(state: Arc<Mutex<InputState>>) -> JoinHandle<()> {
-    thread::spawn(move || {
-        // Set up the callback for input events
-        let state_clone = Arc::clone(&state);
+fn default_idle_after_intervals() -> u32 {
+    60
+}
 
-        // Standard input listening mode for non-Linux platforms or when unstable_grab is not enabled
-        #[cfg(not(all(target_os = "linux", feature = "unstable_grab")))]
-        {
-            let callback = move |event: RdevEvent| {
-                // Check if we should continue running
-                if !RUNNING.load(Ordering::SeqCst) {
-                    // Force exit from the listen loop by panicking the callback
-                    // This is the only reliable way to exit rdev::listen
-                    std::process::exit(0);
-                }
+fn default_afk_threshold() -> u64 {
+    180
+}
 
-                let now = Instant::now();
-                let mut update_activity = false;
+fn default_pulsetime_margin() -> f64 {
+    0.1
+}
 
-                // Lock the state to update
-                if let Ok(mut state_guard) = state_clone.lock() {
-                    match event.event_type {
-                        EventType::KeyPress(_) => {
-                            state_guard.presses += 1;
-                            update_activity = true;
-                        }
-                        EventType::ButtonPress(_) => {
-                            state_guard.clicks += 1;
-                            update_activity = true;
-                        }
-                        EventType::MouseMove { x: _, y: _ } => {
-                            state_guard.delta_x += 1;
-                            state_guard.delta_y += 1;
-                            update_activity = true;
-                        }
-                        EventType::Wheel { delta_x, delta_y } => {
-                            state_guard.scroll_x += delta_x.unsigned_abs();
-                            state_guard.scroll_y += delta_y.unsigned_abs();
-                            update_activity = true;
-                        }
-                        _ => {}
-                    }
+fn default_heartbeat_retries() -> u32 {
+    3
+}
 
-                    if update_activity {
-                        state_guard.last_activity = now;
-                    }
-                }
-            };
+fn default_connect_timeout() -> u64 {
+    5
+}
 
-            // Start listening for input events
-            // Note: This is a blocking call that runs until the process exits
-            if let Err(error) = listen(callback) {
-                eprintln!("Error listening for input events: {:?}", error);
-            }
-        }
+fn default_request_timeout() -> u64 {
+    10
+}
 
-        // Use the grab feature on Linux when enabled
-        // This intercepts events before they reach applications
-        #[cfg(all(target_os = "linux", feature = "unstable_grab"))]
-        {
-            let callback = move |event: RdevEvent| -> Option<RdevEvent> {
-                // Check if we should continue running
-                if !RUNNING.load(Ordering::SeqCst) {
-                    // Force exit from the grab loop
-                    std::process::exit(0);
-                }
+fn default_max_queued_events() -> usize {
+    3600
+}
 
-                let now = Instant::now();
-                let mut update_activity = false;
+fn default_startup_retry_interval() -> u64 {
+    5
+}
 
-                // Lock the state to update
-                if let Ok(mut state_guard) = state_clone.lock() {
-                    match event.event_type {
-                        EventType::KeyPress(_) => {
-                            state_guard.presses += 1;
-                            update_activity = true;
-                        }
-                        EventType::ButtonPress(_) => {
-                            state_guard.clicks += 1;
-                            update_activity = true;
-                        }
-                        EventType::MouseMove { x: _, y: _ } => {
-                            state_guard.delta_x += 1;
-                            state_guard.delta_y += 1;
-                            update_activity = true;
-                        }
-                        EventType::Wheel { delta_x, delta_y } => {
-                            state_guard.scroll_x += delta_x.unsigned_abs();
-                            state_guard.scroll_y += delta_y.unsigned_abs();
-                            update_activity = true;
-                        }
-                        _ => {}
-                    }
+fn default_resume_threshold() -> u64 {
+    60
+}
 
-                    if update_activity {
-                        state_guard.last_activity = now;
-                    }
-                }
+fn default_listener_retries() -> u32 {
+    5
+}
 
-                // Return the event to pass it through without modification
-                Some(event)
-            };
+fn default_double_click_ms() -> u64 {
+    InputOptions::default().double_click_window.as_millis() as u64
+}
 
-            // Start grabbing input events
-            // Note: This is a blocking call that runs until the process exits
-            if let Err(error) = grab(callback) {
-                eprintln!("Error grabbing input events: {:?}", error);
-                eprintln!("Note: On Linux, this program must be run as root or by a user in the 'input' group");
-                eprintln!("To add your user to the input group: sudo usermod -a -G input $USER");
-                eprintln!("You may need to log out and back in for the changes to take effect");
-                std::process::exit(1);
-            }
-        }
+fn default_max_mouse_jump() -> u64 {
+    InputOptions::default()
+        .max_mouse_jump
+        .map_or(0, |max| max as u64)
+}
+
+fn default_scroll_scale() -> f64 {
+    InputOptions::default().scroll_scale
+}
+
+fn default_burst_gap_ms() -> u64 {
+    InputOptions::default().burst_gap.as_millis() as u64
+}
+
+fn default_sinks() -> Vec<SinkKind> {
+    vec![SinkKind::AwServer]
+}
+
+fn default_host() -> String {
+    "localhost".to_string()
+}
+
+fn default_port() -> u16 {
+    5600
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Prefix of the environment variables overriding config keys
+const ENV_PREFIX: &str = "AW_INPUT";
+
+/// Default location of the config file, inside the ActivityWatch config directory
+fn default_config_path() -> Option<PathBuf> {
+    config_dir().map(|dir| {
+        dir.join("activitywatch")
+            .join("aw-watcher-input")
+            .join("config.toml")
     })
 }
 
-/// Command line arguments for aw-watcher-input
-#[derive(Parser, Debug)]
-#[clap(author, version, about = "ActivityWatch Input Watcher")]
-struct Args {
-    /// ActivityWatch server hostname
-    #[clap(long, default_value = "localhost")]
-    host: String,
+/// Log file used when there is no console to log to, in the ActivityWatch log
+/// directory
+fn default_log_path() -> Option<PathBuf> {
+    cache_dir().map(|dir| {
+        dir.join("activitywatch")
+            .join("log")
+            .join("aw-watcher-input")
+            .join("aw-watcher-input.log")
+    })
+}
 
-    /// ActivityWatch server port
-    #[clap(long, default_value = "5600")]
-    port: u16,
+/// Location of the lifetime totals, next to the config file given with
+/// --config or else the default one
+fn totals_path(config_file: Option<&Path>) -> Option<PathBuf> {
+    config_file
+        .map(Path::to_path_buf)
+        .or_else(default_config_path)
+        .map(|path| path.with_file_name("totals.json"))
+}
 
-    /// Use testing mode (different bucket)
-    #[clap(long)]
-    testing: bool,
+/// Location of the lock file for `bucket_id`, next to the config file like
+/// the lifetime totals
+fn lock_path(config_file: Option<&Path>, bucket_id: &str) -> Option<PathBuf> {
+    config_file
+        .map(Path::to_path_buf)
+        .or_else(default_config_path)
+        .map(|path| path.with_file_name(format!("{}.lock", bucket_id)))
+}
 
-    /// Override the polling interval from config (in seconds)
-    #[clap(long)]
-    poll_time: Option<u64>,
+/// Default location of the status socket, in the runtime directory where there
+/// is one and the temporary directory otherwise
+fn default_status_socket_path(bucket_id: &str) -> PathBuf {
+    runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("aw-watcher-input")
+        .join(format!("{}.sock", bucket_id))
 }
 
-fn main() {
-    // Parse command line arguments
-    let args = Args::parse();
+/// Config file listing every supported key with its default value and a description
+fn config_template() -> String {
+    let defaults = AppConfig::default();
+    format!(
+        r#"# Configuration for aw-watcher-input
 
-    // Load configuration
-    let config = match AppConfig::new() {
-        Ok(config) => config,
-        Err(e) => {
-            eprintln!("Error loading configuration: {}", e);
-            AppConfig {
-                polling_interval: default_polling_interval(),
-            }
-        }
-    };
+# Polling interval in seconds, fractions like 0.5 allow sub-second polling
+polling_interval = {:?}
 
-    // Use poll_time from args if provided, otherwise from config
-    let polling_interval = args.poll_time.unwrap_or(config.polling_interval);
+# Polling interval in seconds used after idle_after_intervals intervals in a row
+# without input, until the next input. 0 always polls at polling_interval
+idle_polling_interval = {:?}
 
-    // Get hostname and create bucket ID with hostname appended
-    let hostname = match get_hostname() {
-        Ok(name) => name.to_string_lossy().into_owned(),
-        Err(_) => "unknown-host".to_string(),
-    };
+# Number of intervals in a row without input before polling at idle_polling_interval
+idle_after_intervals = {}
 
-    // Add testing suffix if in testing mode
-    let bucket_id = if args.testing {
-        format!("aw-watcher-input-testing_{}", hostname)
-    } else {
-        format!("aw-watcher-input_{}", hostname)
-    };
-    let event_type = "os.hid.input";
+# Polling interval in seconds while running on battery, needs the battery feature.
+# 0 polls at polling_interval regardless of the power source
+battery_polling_interval = {:?}
 
-    println!(
-        "Starting aw-watcher-input-rs with polling interval of {} seconds",
-        polling_interval
-    );
-    println!("Using bucket ID: {}", bucket_id);
-    println!("Connecting to aw-server at {}:{}", args.host, args.port);
-    if args.testing {
-        println!("Running in testing mode");
-    }
+# Seconds without input after which the user is considered AFK
+afk_threshold = {}
 
-    // Set up Ctrl+C handler
-    RUNNING.store(true, Ordering::SeqCst);
-    ctrlc::set_handler(move || {
-        println!("\nReceived Ctrl+C, shutting down gracefully...");
-        RUNNING.store(false, Ordering::SeqCst);
+# Input that counts as activity for the AFK decision: "any", "keyboard" or "mouse"
+afk_input = "{}"
 
-        // Give the application a moment to clean up
-        sleep(Duration::from_millis(100));
+# Also report AFK status to an aw-watcher-afk compatible bucket
+afk = {}
 
-        // Force exit if graceful shutdown doesn't work
-        // This is necessary because rdev::listen cannot be interrupted gracefully
-        std::process::exit(0);
-    })
-    .expect("Error setting Ctrl-C handler");
+# Seconds added to the polling interval to get the heartbeat pulsetime
+pulsetime_margin = {:?}
 
-    let client = AwClient::new(&args.host, args.port, "aw-watcher-input").unwrap();
+# Up to this many milliseconds of random delay before each heartbeat, so that
+# many watchers started together don't hit the server at once. Event timestamps
+# and durations stay on the polling grid
+jitter_ms = {}
 
-    // Create or get bucket
-    client
-        .create_bucket_simple(&bucket_id, event_type)
-        .expect("Failed to create input bucket");
+# Seconds of recent intervals to compute the smoothed window_* rates over, such
+# as window_wpm, independent of polling_interval. 0 leaves them out
+aggregation_window = {:?}
 
-    // Setup shared state for input monitoring
-    let input_state = Arc::new(Mutex::new(InputState::default()));
+# When every cycle keeps taking longer than the polling interval, raise the
+# interval to fit instead of only suggesting a larger one in the log
+raise_interval_on_overrun = {}
 
-    // Start the input monitoring thread
-    let _listener_thread = create_input_listener_thread(Arc::clone(&input_state));
+# Number of times a failed heartbeat is retried before giving up
+heartbeat_retries = {}
 
-    println!("Input monitoring thread started");
+# Seconds to wait for a connection to the server before counting a
+# heartbeat as failed
+connect_timeout = {}
 
-    #[cfg(not(all(target_os = "linux", feature = "unstable_grab")))]
-    println!("Input detection is now active using rdev listen mode");
+# Seconds to wait for the server to answer a heartbeat before counting it
+# as failed, 0 waits indefinitely
+request_timeout = {}
 
-    #[cfg(all(target_os = "linux", feature = "unstable_grab"))]
-    {
-        println!("Input detection is now active using rdev grab mode (Linux)");
-        println!("NOTE: This requires your user to be in the 'input' group or to run as root");
-        println!("To add your user to the input group: sudo usermod -a -G input $USER");
-        println!("On some distributions, you may need to use the 'plugdev' group instead");
-        println!("You must log out and back in for group changes to take effect");
-    }
+# Maximum number of events kept in memory while the server is unreachable
+max_queued_events = {}
 
-    println!("Press Ctrl+C to exit");
+# Seconds to wait between attempts to reach the server at startup
+startup_retry_interval = {}
 
-    // Main polling loop
-    while RUNNING.load(Ordering::SeqCst) {
-        // Record the start time of this iteration
-        let loop_start = Instant::now();
-        let timestamp = Utc::now();
+# Number of attempts to reach the server at startup before giving up, 0 retries forever
+startup_retries = {}
 
-        // Get current input state and reset counters
-        let data = {
-            if let Ok(mut state_guard) = input_state.lock() {
-                let data = InputState {
-                    presses: state_guard.presses,
-                    clicks: state_guard.clicks,
-                    delta_x: state_guard.delta_x,
-                    delta_y: state_guard.delta_y,
-                    scroll_x: state_guard.scroll_x,
-                    scroll_y: state_guard.scroll_y,
-                    last_activity: state_guard.last_activity,
-                };
+# Seconds the wall clock may run ahead of the polling interval before the gap
+# is treated as a system sleep and skipped, 0 disables the detection
+resume_threshold = {}
 
-                // Reset counters for the next period, but keep the last_activity time
-                let last_activity = state_guard.last_activity;
-                *state_guard = InputState {
-                    last_activity,
-                    ..Default::default()
-                };
-                data
-            } else {
-                // If we can't lock the state, use default values
-                InputState::default()
-            }
-        };
+# Number of times input capture is restarted after an error before giving up
+listener_retries = {}
 
-        // Create event data
-        let mut data_map = Map::new();
-        data_map.insert("presses".to_string(), Value::Number(data.presses.into()));
-        data_map.insert("clicks".to_string(), Value::Number(data.clicks.into()));
-        data_map.insert("deltaX".to_string(), Value::Number(data.delta_x.into()));
-        data_map.insert("deltaY".to_string(), Value::Number(data.delta_y.into()));
-        data_map.insert("scrollX".to_string(), Value::Number(data.scroll_x.into()));
-        data_map.insert("scrollY".to_string(), Value::Number(data.scroll_y.into()));
+# In grab mode, fall back to listen mode when grabbing isn't permitted
+grab_fallback = {}
 
-        let event = Event {
-            id: None,
-            timestamp,
-            duration: TimeDelta::seconds(polling_interval as i64),
-            data: data_map.clone(),
-        };
+# Add the capture mode in use, grab or listen, to every input event
+report_capture_mode = {}
+
+# Refuse to start while another instance feeds the same bucket
+single_instance = {}
+
+# Leave intervals without any input out of the bucket
+skip_empty = {}
+
+# Mark when the watcher starts and stops with zero-length events in the input
+# bucket, carrying {{"event": "session_start"}} and {{"event": "session_stop"}}
+session_events = {}
+
+# Send each day's presses, clicks and distance to the aw-watcher-input-daily
+# bucket once the local date changes, needs lifetime_totals
+daily_summary = {}
+
+# aw-server to send heartbeats to, --host and --port take precedence
+host = "{}"
+port = {}
+
+# Connect over "http" or "https", --scheme takes precedence
+scheme = "{}"
+
+# PEM certificate to trust for https in addition to the system's, e.g. for a
+# self-signed server, --ca-cert takes precedence
+# ca_cert = "/path/to/server-ca.pem"
+
+# Hostname to use in bucket IDs instead of the detected one, e.g. for cloned VMs
+# hostname = "my-machine"
+
+# Bucket ID to use instead of aw-watcher-input_<hostname>
+# bucket_id = "aw-watcher-input_my-machine"
+
+# Client name reported to aw-server
+# client_id = "aw-watcher-input"
+
+# On Unix, serve the live counters as JSON on a local socket
+status_socket = {}
+
+# Path of the status socket instead of <runtime dir>/aw-watcher-input/<bucket_id>.sock
+# status_socket_path = "/run/user/1000/aw-watcher-input/status.sock"
+
+# Keep all-time totals of presses, clicks and distance in totals.json next to
+# this file, they survive restarts and are never sent to aw-server
+lifetime_totals = {}
+
+# Only report whether there was any input, without counting it
+privacy_mode = {}
+
+# Local time ranges during which nothing is recorded or sent, such as
+# ["22:00-07:00", "12:00-13:00"]. Ranges may cross midnight
+quiet_hours = [{}]
+
+# Record key presses
+track_keyboard = {}
+
+# Record mouse button clicks
+track_mouse_buttons = {}
 
-        // Calculate pulsetime based on whether input occurred during this polling interval
-        let pulsetime = polling_interval as f64 + 0.1;
+# Record mouse movement
+track_mouse_move = {}
 
-        // Debug output
+# Record scroll wheel activity
+track_scroll = {}
+
+# Longest gap in milliseconds between two clicks of the same button that
+# counts as a double-click
+double_click_ms = {}
+
+# Single mouse moves longer than this many pixels are treated as the cursor
+# being warped, e.g. to another monitor, and add no distance, 0 disables the filter
+max_mouse_jump = {}
+
+# Factor applied to the raw scroll deltas so they come out in wheel notches. The
+# default is 1.0 on Linux and Windows, and 0.1 on macOS, which reports pixel deltas
+scroll_scale = {:?}
+
+# Input after a pause longer than this many milliseconds starts a new burst
+burst_gap_ms = {}
+
+# Where heartbeats are sent, any of "aw-server", "jsonl", "sqlite" and "stdout".
+# Every sink gets each heartbeat, and a failing sink doesn't hold up the others
+sinks = [{}]
+
+# File the jsonl sink appends input events to, one JSON object per line
+# output_file = "/home/me/aw-watcher-input.jsonl"
+
+# Database the sqlite sink logs input events to, needs the buffer_db feature
+# sqlite_file = "/home/me/aw-watcher-input.db"
+"#,
+        defaults.polling_interval,
+        defaults.idle_polling_interval,
+        defaults.idle_after_intervals,
+        defaults.battery_polling_interval,
+        defaults.afk_threshold,
+        defaults.afk_input.as_str(),
+        defaults.afk,
+        defaults.pulsetime_margin,
+        defaults.jitter_ms,
+        defaults.aggregation_window,
+        defaults.raise_interval_on_overrun,
+        defaults.heartbeat_retries,
+        defaults.connect_timeout,
+        defaults.request_timeout,
+        defaults.max_queued_events,
+        defaults.startup_retry_interval,
+        defaults.startup_retries,
+        defaults.resume_threshold,
+        defaults.listener_retries,
+        defaults.grab_fallback,
+        defaults.report_capture_mode,
+        defaults.single_instance,
+        defaults.skip_empty,
+        defaults.session_events,
+        defaults.daily_summary,
+        defaults.host,
+        defaults.port,
+        defaults.scheme.as_str(),
+        defaults.status_socket,
+        defaults.lifetime_totals,
+        defaults.privacy_mode,
+        defaults
+            .quiet_hours
+            .iter()
+            .map(|window| format!("\"{}\"", window))
+            .collect::<Vec<_>>()
+            .join(", "),
+        defaults.track_keyboard,
+        defaults.track_mouse_buttons,
+        defaults.track_mouse_move,
+        defaults.track_scroll,
+        defaults.double_click_ms,
+        defaults.max_mouse_jump,
+        defaults.scroll_scale,
+        defaults.burst_gap_ms,
+        defaults
+            .sinks
+            .iter()
+            .map(|kind| format!("\"{}\"", kind.as_str()))
+            .collect::<Vec<_>>()
+            .join(", "),
+    )
+}
+
+/// Lock a file for as long as the returned handle lives, so that a second watcher
+/// can't feed the same bucket. The OS drops the lock when the process exits,
+/// so a file left behind by a dead instance is simply taken over.
+fn lock_instance(path: &Path) -> Result<std::fs::File, String> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)
+            .map_err(|e| format!("could not create {}: {}", parent.display(), e))?;
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| format!("could not open {}: {}", path.display(), e))?;
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            // Windows locks block reading as well, so the PID may be unknown
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            let holder = match pid.trim() {
+                "" => "another instance".to_string(),
+                pid => format!("another instance (PID {})", pid),
+            };
+            return Err(format!(
+                "{} is already running, pass --force to start anyway",
+                holder
+            ));
+        }
+        Err(TryLockError::Error(e)) => {
+            return Err(format!("could not lock {}: {}", path.display(), e));
+        }
+    }
+
+    file.set_len(0)
+        .and_then(|()| write!(file, "{}", std::process::id()))
+        .map_err(|e| format!("could not write {}: {}", path.display(), e))?;
+    Ok(file)
+}
+
+/// Install or uninstall the LaunchAgent for --install-service and
+/// --uninstall-service, exiting on errors
+fn manage_service(args: &Args) {
+    if !cfg!(target_os = "macos") {
+        error!("--install-service and --uninstall-service are only available on macOS");
+        std::process::exit(1);
+    }
+    let Some(path) = service::plist_path() else {
+        error!("Could not determine the home directory");
+        std::process::exit(1);
+    };
+
+    if args.uninstall_service {
+        if let Err(e) = service::uninstall(&path) {
+            error!("Error removing {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+        info!("Removed the LaunchAgent {}", path.display());
         println!(
-            "Heartbeat: presses={}, clicks={}, deltaX={}, deltaY={}, scrollX={}, scrollY={}",
-            data.presses, data.clicks, data.delta_x, data.delta_y, data.scroll_x, data.scroll_y
+            "To stop the watcher started by it, run:\n  launchctl remove {}",
+            service::LABEL
         );
+        return;
+    }
 
-        // Send the heartbeat
-        match client.heartbeat(&bucket_id, &event, pulsetime) {
-            Ok(_) => (),
-            Err(e) => eprintln!("Error sending heartbeat: {}", e),
+    let executable = match std::env::current_exe().and_then(|path| path.canonicalize()) {
+        Ok(path) => path,
+        Err(e) => {
+            error!("Could not determine the path of the watcher: {}", e);
+            std::process::exit(1);
         }
+    };
+    let arguments = match std::env::current_dir() {
+        Ok(cwd) => service::service_arguments(std::env::args().skip(1), &cwd),
+        Err(e) => {
+            error!("Could not determine the current directory: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // With --log-file the logs already end up in a file of the user's choice
+    let log = service::default_log_path().filter(|_| args.log_file.is_none());
+    let plist = service::launch_agent_plist(&executable, &arguments, log.as_deref());
+    if let Err(e) = service::install(&path, &plist, args.overwrite_service) {
+        error!("Error writing {}: {}", path.display(), e);
+        std::process::exit(1);
+    }
+    info!("Wrote the LaunchAgent {}", path.display());
+    println!(
+        "To start the watcher now and at every login, run:\n  launchctl load -w {}",
+        path.display()
+    );
+}
 
-        // Calculate how much time has elapsed in this iteration
-        let elapsed = loop_start.elapsed();
+/// Write the config template for --generate-config, refusing to overwrite an
+/// existing file unless `force` is set
+fn generate_config(path: &Path, force: bool) -> io::Result<()> {
+    if path.exists() && !force {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "config file already exists, pass --force to overwrite it",
+        ));
+    }
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    write(path, config_template())
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            polling_interval: default_polling_interval(),
+            idle_polling_interval: 0.0,
+            idle_after_intervals: default_idle_after_intervals(),
+            battery_polling_interval: 0.0,
+            afk_threshold: default_afk_threshold(),
+            afk_input: AfkInput::Any,
+            afk: false,
+            pulsetime_margin: default_pulsetime_margin(),
+            jitter_ms: 0,
+            aggregation_window: 0.0,
+            raise_interval_on_overrun: false,
+            heartbeat_retries: default_heartbeat_retries(),
+            connect_timeout: default_connect_timeout(),
+            request_timeout: default_request_timeout(),
+            max_queued_events: default_max_queued_events(),
+            startup_retry_interval: default_startup_retry_interval(),
+            startup_retries: 0,
+            resume_threshold: default_resume_threshold(),
+            listener_retries: default_listener_retries(),
+            grab_fallback: default_true(),
+            report_capture_mode: false,
+            single_instance: default_true(),
+            skip_empty: false,
+            session_events: false,
+            daily_summary: false,
+            host: default_host(),
+            port: default_port(),
+            scheme: Scheme::Http,
+            ca_cert: None,
+            hostname: None,
+            bucket_id: None,
+            client_id: None,
+            status_socket: false,
+            status_socket_path: None,
+            lifetime_totals: default_true(),
+            privacy_mode: false,
+            quiet_hours: Vec::new(),
+            track_keyboard: default_true(),
+            track_mouse_buttons: default_true(),
+            track_mouse_move: default_true(),
+            track_scroll: default_true(),
+            double_click_ms: default_double_click_ms(),
+            max_mouse_jump: default_max_mouse_jump(),
+            scroll_scale: default_scroll_scale(),
+            burst_gap_ms: default_burst_gap_ms(),
+            sinks: default_sinks(),
+            output_file: None,
+            sqlite_file: None,
+        }
+    }
+}
 
-        // Calculate the time to sleep to maintain consistent intervals
-        if elapsed < Duration::from_secs(polling_interval) {
-            let sleep_time = Duration::from_secs(polling_interval) - elapsed;
+impl AppConfig {
+    /// Load the configuration from `path` if given. Otherwise the default location
+    /// is used, and a default config file is written there if it doesn't exist yet.
+    fn new(path: Option<PathBuf>) -> Result<Self, ConfigError> {
+        let default_config = Self::default();
 
-            // Sleep in smaller intervals to be more responsive to shutdown signals
-            let sleep_interval = Duration::from_millis(100);
-            let mut remaining = sleep_time;
+        let config_path = if let Some(path) = path {
+            // An explicitly requested config file must exist
+            if !path.exists() {
+                return Err(ConfigError::NotFound(path.display().to_string()));
+            }
+            Some(path)
+        } else if let Some(config_file) = default_config_path() {
+            if let Some(aw_config_dir) = config_file.parent() {
+                create_dir_all(aw_config_dir).ok();
+            }
 
-            while remaining > Duration::from_millis(0) && RUNNING.load(Ordering::SeqCst) {
-                let current_sleep = if remaining > sleep_interval {
-                    sleep_interval
-                } else {
-                    remaining
-                };
-                sleep(current_sleep);
-                remaining = remaining.saturating_sub(current_sleep);
+            if !config_file.exists() {
+                let default_config_str = toml::to_string_pretty(&default_config).unwrap();
+                write(&config_file, default_config_str).ok();
             }
+
+            Some(config_file)
         } else {
-            // If operations took longer than polling_interval, don't sleep
-            // but log a warning about the missed interval
-            eprintln!(
-                "Warning: Operations took longer than polling interval ({:?} > {}s)",
-                elapsed, polling_interval
-            );
+            None
+        };
+
+        let mut builder = Config::builder();
+
+        if let Some(path) = config_path {
+            if path.exists() {
+                builder = builder.add_source(File::from(path));
+            }
         }
-    }
 
-    println!("Graceful shutdown complete.");
+        // Environment variables named after the keys, such as
+        // AW_INPUT_POLLING_INTERVAL, take precedence over the file
+        builder = builder.add_source(
+            Environment::with_prefix(ENV_PREFIX)
+                .try_parsing(true)
+                .list_separator(",")
+                .with_list_parse_key("sinks")
+                .with_list_parse_key("quiet_hours"),
+        );
+
+        // Report parse errors instead of falling back to defaults, the error
+        // names the offending key so the user knows which setting is wrong
+        let config: Self = builder.build()?.try_deserialize()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check values that parse fine but make no sense
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !self.polling_interval.is_finite() || self.polling_interval <= 0.0 {
+            return Err(ConfigError::Message(format!(
+                "polling_interval must be a number greater than 0, got {}",
+                self.polling_interval
+            )));
+        }
+        if !self.idle_polling_interval.is_finite() || self.idle_polling_interval < 0.0 {
+            return Err(ConfigError::Message(format!(
+                "idle_polling_interval must be a non-negative number, got {}",
+                self.idle_polling_interval
+            )));
+        }
+        if !self.battery_polling_interval.is_finite() || self.battery_polling_interval < 0.0 {
+            return Err(ConfigError::Message(format!(
+                "battery_polling_interval must be a non-negative number, got {}",
+                self.battery_polling_interval
+            )));
+        }
+        if !self.aggregation_window.is_finite() || self.aggregation_window < 0.0 {
+            return Err(ConfigError::Message(format!(
+                "aggregation_window must be a non-negative number, got {}",
+                self.aggregation_window
+            )));
+        }
+        if !self.pulsetime_margin.is_finite() || self.pulsetime_margin < 0.0 {
+            return Err(ConfigError::Message(format!(
+                "pulsetime_margin must be a non-negative number, got {}",
+                self.pulsetime_margin
+            )));
+        }
+        if self.daily_summary && !self.lifetime_totals {
+            return Err(ConfigError::Message(
+                "daily_summary needs lifetime_totals to be enabled".to_string(),
+            ));
+        }
+        for window in &self.quiet_hours {
+            QuietWindow::parse(window).map_err(|e| {
+                ConfigError::Message(format!("invalid quiet_hours entry '{}': {}", window, e))
+            })?;
+        }
+        if !self.scroll_scale.is_finite() || self.scroll_scale <= 0.0 {
+            return Err(ConfigError::Message(format!(
+                "scroll_scale must be a number greater than 0, got {}",
+                self.scroll_scale
+            )));
+        }
+        if self.connect_timeout == 0 {
+            return Err(ConfigError::Message(
+                "connect_timeout must be greater than 0".to_string(),
+            ));
+        }
+        if self.burst_gap_ms == 0 {
+            return Err(ConfigError::Message(
+                "burst_gap_ms must be greater than 0".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// How the sender thread retries heartbeats and how long it waits for each
+    fn sender_settings(&self) -> SenderSettings {
+        SenderSettings {
+            retries: self.heartbeat_retries,
+            connect_timeout: Duration::from_secs(self.connect_timeout),
+            request_timeout: (self.request_timeout > 0)
+                .then(|| Duration::from_secs(self.request_timeout)),
+        }
+    }
+
+    /// The parsed quiet_hours, invalid entries are rejected by validate()
+    fn quiet_windows(&self) -> Vec<QuietWindow> {
+        self.quiet_hours
+            .iter()
+            .filter_map(|window| QuietWindow::parse(window).ok())
+            .collect()
+    }
+
+    /// How the input state interprets events
+    fn input_options(&self) -> InputOptions {
+        InputOptions {
+            double_click_window: Duration::from_millis(self.double_click_ms),
+            max_mouse_jump: (self.max_mouse_jump > 0).then_some(self.max_mouse_jump as f64),
+            scroll_scale: self.scroll_scale,
+            burst_gap: Duration::from_millis(self.burst_gap_ms),
+        }
+    }
+}
+
+/// A daily range of local time during which nothing is tracked, in minutes
+/// since midnight. The end is exclusive and may be earlier than the start for
+/// ranges crossing midnight.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct QuietWindow {
+    start: u32,
+    end: u32,
+}
+
+impl QuietWindow {
+    /// Parse a range like `22:00-07:00`
+    fn parse(input: &str) -> Result<Self, String> {
+        let time = |part: &str| -> Result<u32, String> {
+            let (hours, minutes) = part
+                .trim()
+                .split_once(':')
+                .ok_or_else(|| format!("expected HH:MM, got '{}'", part.trim()))?;
+            match (hours.parse::<u32>(), minutes.parse::<u32>()) {
+                (Ok(hours), Ok(minutes)) if hours < 24 && minutes < 60 => Ok(hours * 60 + minutes),
+                _ => Err(format!("'{}' is not a time of day", part.trim())),
+            }
+        };
+        let (start, end) = input
+            .split_once('-')
+            .ok_or_else(|| "expected a range like 22:00-07:00".to_string())?;
+        let window = Self {
+            start: time(start)?,
+            end: time(end)?,
+        };
+        if window.start == window.end {
+            return Err("the range is empty".to_string());
+        }
+        Ok(window)
+    }
+
+    /// Whether `minute` minutes after midnight fall into the range
+    fn contains(&self, minute: u32) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// The configured sinks adjusted for the command line: --dry-run prints instead
+/// of contacting aw-server, --no-server leaves it out and --output-file adds
+/// the jsonl sink
+fn selected_sinks(
+    configured: &[SinkKind],
+    dry_run: bool,
+    no_server: bool,
+    output_file: bool,
+) -> Vec<SinkKind> {
+    let mut sinks = Vec::new();
+    let extra = [(dry_run, SinkKind::Stdout), (output_file, SinkKind::Jsonl)];
+    let wanted = configured.iter().copied().chain(
+        extra
+            .into_iter()
+            .filter_map(|(enabled, kind)| enabled.then_some(kind)),
+    );
+    for kind in wanted {
+        let skipped = kind == SinkKind::AwServer && (dry_run || no_server);
+        if !skipped && !sinks.contains(&kind) {
+            sinks.push(kind);
+        }
+    }
+    sinks
+}
+
+/// Time without any input on Wayland after which capture is reported as
+/// likely broken
+#[cfg(target_os = "linux")]
+const WAYLAND_IDLE_WARNING: Duration = Duration::from_secs(300);
+
+/// Difference between wall-clock and monotonic time over one interval that is
+/// treated as a clock adjustment rather than jitter
+const CLOCK_JUMP_TOLERANCE: Duration = Duration::from_secs(1);
+
+// Global atomic for requesting a config reload, set on SIGHUP
+static RELOAD_CONFIG: AtomicBool = AtomicBool::new(false);
+
+// Global atomic for pausing tracking by hand, toggled on SIGUSR1 and cleared
+// on SIGUSR2
+static PAUSE_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Global atomic for sending the interval so far right away, set by the flush
+// command of the status socket
+static FLUSH_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Handle Unix signals on a dedicated thread, outside of signal handler context
+#[cfg(unix)]
+fn spawn_signal_thread() {
+    use signal_hook::consts::{SIGHUP, SIGTERM, SIGUSR1, SIGUSR2};
+    use signal_hook::iterator::Signals;
+
+    let mut signals = match Signals::new([SIGHUP, SIGTERM, SIGUSR1, SIGUSR2]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            warn!("Could not install signal handlers: {}", e);
+            return;
+        }
+    };
+
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            match signal {
+                SIGHUP => {
+                    info!("Received SIGHUP, reloading configuration");
+                    RELOAD_CONFIG.store(true, Ordering::SeqCst);
+                }
+                SIGUSR1 => {
+                    if PAUSE_REQUESTED.fetch_xor(true, Ordering::SeqCst) {
+                        info!("Received SIGUSR1, resuming tracking");
+                    } else {
+                        info!("Received SIGUSR1, pausing tracking");
+                    }
+                }
+                SIGUSR2 => {
+                    if PAUSE_REQUESTED.swap(false, Ordering::SeqCst) {
+                        info!("Received SIGUSR2, resuming tracking");
+                    } else {
+                        debug!("Received SIGUSR2, tracking is not paused");
+                    }
+                }
+                // Service managers stop the watcher with SIGTERM, which takes
+                // the same graceful path as Ctrl+C
+                SIGTERM => {
+                    if !RUNNING.swap(false, Ordering::SeqCst) {
+                        std::process::exit(1);
+                    }
+                    info!("Received SIGTERM, shutting down gracefully...");
+                }
+                _ => {}
+            }
+        }
+    });
+}
+
+/// Keep running a startup operation until it succeeds, waiting `interval` between
+/// attempts and giving up after `retries` failures unless it is 0.
+/// Exits the process cleanly if shutdown is requested while waiting.
+fn retry_until_ready<T, E: std::fmt::Display>(
+    what: &str,
+    interval: Duration,
+    retries: u32,
+    mut operation: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut attempt = 1;
+    loop {
+        match operation() {
+            Ok(value) => return Ok(value),
+            Err(e) if retries == 0 || attempt < retries => {
+                warn!(
+                    "Failed to {} (attempt {}): {}, retrying in {:?}",
+                    what, attempt, e, interval
+                );
+            }
+            Err(e) => return Err(e),
+        }
+
+        // Wait in small steps so Ctrl+C during startup still exits promptly
+        let deadline = Instant::now() + interval;
+        while Instant::now() < deadline {
+            if !RUNNING.load(Ordering::SeqCst) {
+                info!("Shutdown requested during startup, exiting");
+                std::process::exit(0);
+            }
+            sleep(Duration::from_millis(100));
+        }
+        attempt += 1;
+    }
+}
+
+/// Whether the session runs on Wayland, where rdev's listen mode only sees
+/// input sent to XWayland windows
+#[cfg(target_os = "linux")]
+fn is_wayland_session() -> bool {
+    std::env::var("XDG_SESSION_TYPE").is_ok_and(|session| session.eq_ignore_ascii_case("wayland"))
+        || std::env::var_os("WAYLAND_DISPLAY").is_some()
+}
+
+/// How to switch to grab mode, which needs unstable_grab compiled in
+#[cfg(target_os = "linux")]
+fn grab_hint() -> &'static str {
+    if cfg!(feature = "unstable_grab") {
+        "Run with --capture-mode grab to read the input devices directly"
+    } else {
+        "Build with --features unstable_grab and run with --capture-mode grab to read the input devices directly"
+    }
+}
+
+/// Scale a count over `duration` to a rate per minute, an empty interval has a
+/// rate of 0 rather than infinity
+fn per_minute(count: u64, duration: TimeDelta) -> f64 {
+    let millis = duration.num_milliseconds();
+    if millis <= 0 {
+        return 0.0;
+    }
+    count as f64 * 60_000.0 / millis as f64
+}
+
+/// Polling interval in seconds for the next interval, the idle one once
+/// `empty_intervals` intervals in a row went without input
+fn adaptive_interval(polling_interval: f64, config: &AppConfig, empty_intervals: u32) -> f64 {
+    if config.idle_polling_interval > 0.0 && empty_intervals >= config.idle_after_intervals {
+        config.idle_polling_interval
+    } else {
+        polling_interval
+    }
+}
+
+/// Number of overrunning cycles in a row after which the polling interval is
+/// considered too short for the machine
+const OVERRUN_STREAK: u32 = 5;
+
+/// Counts cycles in a row that took longer than the polling interval
+#[derive(Debug, Default)]
+struct OverrunTracker {
+    streak: u32,
+}
+
+impl OverrunTracker {
+    /// Note whether the last cycle overran, returning true once per streak
+    /// when it reaches [`OVERRUN_STREAK`]
+    fn record(&mut self, overran: bool) -> bool {
+        self.streak = if overran {
+            self.streak.saturating_add(1)
+        } else {
+            0
+        };
+        self.streak == OVERRUN_STREAK
+    }
+}
+
+/// Polling interval in seconds with room for a cycle taking `elapsed`, half
+/// as long again and rounded up to a tenth of a second
+fn suggested_interval(elapsed: Duration) -> f64 {
+    (elapsed.as_secs_f64() * 15.0).ceil() / 10.0
+}
+
+/// How much the wall clock was adjusted while `monotonic_elapsed` passed on
+/// the monotonic clock, if it is more than the usual jitter. Positive when the
+/// clock jumped forward.
+fn clock_jump(wall_elapsed: TimeDelta, monotonic_elapsed: Duration) -> Option<TimeDelta> {
+    let monotonic = TimeDelta::from_std(monotonic_elapsed).unwrap_or(TimeDelta::zero());
+    let jump = wall_elapsed - monotonic;
+    let beyond_jitter = jump
+        .abs()
+        .to_std()
+        .is_ok_and(|size| size > CLOCK_JUMP_TOLERANCE);
+    beyond_jitter.then_some(jump)
+}
+
+/// Random delay below `max`, or none if `max` is zero. Each `RandomState` is
+/// seeded differently, which is plenty to spread heartbeats out.
+fn jitter(max: Duration) -> Duration {
+    let Ok(max_nanos) = u64::try_from(max.as_nanos()) else {
+        return Duration::ZERO;
+    };
+    if max_nanos == 0 {
+        return Duration::ZERO;
+    }
+    let random = RandomState::new().build_hasher().finish();
+    Duration::from_nanos(random % max_nanos)
+}
+
+/// Latest tick of the polling grid that has been reached by `now`, starting
+/// from `next_tick`. When operations overran, the missed ticks are skipped and
+/// the current interval is stretched to cover them.
+fn last_tick(next_tick: Instant, interval: Duration, now: Instant) -> Instant {
+    let mut tick = next_tick;
+    while tick + interval <= now {
+        tick += interval;
+    }
+    tick
+}
+
+/// The scheme is chosen with --scheme, so a URL like `https://example.com`
+/// would silently fail or, worse, be sent unencrypted
+fn validate_host(host: &str) -> Result<(), String> {
+    if let Some((scheme, _)) = host.split_once("://") {
+        let hint = if scheme.eq_ignore_ascii_case("https") {
+            ", pass --scheme https to connect over HTTPS"
+        } else {
+            ""
+        };
+        return Err(format!(
+            "--host takes a hostname, not a URL like {}{}",
+            host, hint
+        ));
+    }
+    Ok(())
+}
+
+/// Longest hostname kept in bucket IDs, the DNS label limit
+const MAX_HOSTNAME_LENGTH: usize = 63;
+
+/// Hostname normalized for bucket IDs: lowercased, with everything outside
+/// `[a-z0-9-]` replaced by `-` and cut to [`MAX_HOSTNAME_LENGTH`], so the same
+/// machine always gets the same bucket
+fn sanitize_hostname(hostname: &str) -> String {
+    let sanitized: String = hostname
+        .to_lowercase()
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '-' => c,
+            _ => '-',
+        })
+        .collect();
+    let mut sanitized = sanitized.trim_matches('-');
+    if sanitized.len() > MAX_HOSTNAME_LENGTH {
+        // Only ASCII is left, so any byte index is a char boundary
+        let truncated = sanitized[..MAX_HOSTNAME_LENGTH].trim_end_matches('-');
+        warn!(
+            "Hostname {} is longer than {} characters, using {} in bucket IDs",
+            hostname, MAX_HOSTNAME_LENGTH, truncated
+        );
+        sanitized = truncated;
+    }
+    if sanitized.is_empty() {
+        "unknown-host".to_string()
+    } else {
+        sanitized.to_string()
+    }
+}
+
+/// Parse the --capture-mode value
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_capture_mode(input: &str) -> Result<CaptureMode, String> {
+    match input {
+        "listen" => Ok(CaptureMode::Listen),
+        "grab" => Ok(CaptureMode::Grab),
+        _ => Err(format!("expected 'listen' or 'grab', got '{}'", input)),
+    }
+}
+
+/// Parse a human-friendly duration such as `90`, `30s`, `10m`, `2h` or `1h30m`,
+/// where bare numbers are seconds
+fn parse_duration(input: &str) -> Result<Duration, String> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err("empty duration".to_string());
+    }
+    if let Ok(seconds) = input.parse::<u64>() {
+        return Ok(Duration::from_secs(seconds));
+    }
+
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    for c in input.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(format!("unknown unit '{}' in '{}'", c, input)),
+        };
+        if number.is_empty() {
+            return Err(format!("missing number before '{}' in '{}'", c, input));
+        }
+        total = number
+            .parse::<u64>()
+            .ok()
+            .and_then(|value| value.checked_mul(unit))
+            .and_then(|seconds| total.checked_add(seconds))
+            .ok_or("duration too large")?;
+        number.clear();
+    }
+    if number.len() == input.len() {
+        // All digits, but too many for the number of seconds
+        return Err("duration too large".to_string());
+    }
+    if !number.is_empty() {
+        return Err(format!("missing unit after {} in '{}'", number, input));
+    }
+    Ok(Duration::from_secs(total))
+}
+
+/// Connect to aw-server at `server_url` and create the watcher's buckets, as
+/// used by --check
+fn check_server(
+    api: &server::ServerApi,
+    server_url: &str,
+    buckets: &[Bucket],
+) -> Result<(), String> {
+    let version = api
+        .version()
+        .map_err(|e| format!("could not reach aw-server at {}: {}", server_url, e))?;
+    println!("Connected to aw-server {} on {}", version, server_url);
+
+    for bucket in buckets {
+        api.create_bucket(bucket)
+            .map_err(|e| format!("could not create bucket {}: {}", bucket.id, e))?;
+        println!("Bucket {} is ready", bucket.id);
+    }
+    Ok(())
+}
+
+/// Zero-length marker for the watcher starting or stopping, `kind` being
+/// `session_start` or `session_stop`
+fn session_event(kind: &str) -> Event {
+    let mut data = Map::new();
+    data.insert("event".to_string(), Value::from(kind));
+    Event {
+        id: None,
+        timestamp: Utc::now(),
+        duration: TimeDelta::zero(),
+        data,
+    }
+}
+
+/// Rollup of a finished day, spanning it from local midnight to midnight.
+/// `None` if the date can't be read.
+fn daily_event(day: &DayTotals) -> Option<Event> {
+    let date = NaiveDate::parse_from_str(&day.date, "%Y-%m-%d").ok()?;
+    let midnight = |date: NaiveDate| {
+        date.and_hms_opt(0, 0, 0)?
+            .and_local_timezone(Local)
+            .earliest()
+            .map(|midnight| midnight.with_timezone(&Utc))
+    };
+    let start = midnight(date)?;
+    let end = midnight(date.succ_opt()?)?;
+
+    let mut data = Map::new();
+    data.insert("date".to_string(), Value::from(day.date.as_str()));
+    data.insert("presses".to_string(), Value::from(day.presses));
+    data.insert("clicks".to_string(), Value::from(day.clicks));
+    data.insert("distance".to_string(), Value::from(day.distance));
+    Some(Event {
+        id: None,
+        timestamp: start,
+        duration: end - start,
+        data,
+    })
+}
+
+/// Bucket data describing the machine and watcher, so buckets synced from
+/// several hosts can be told apart
+fn bucket_metadata(capture_mode: CaptureMode) -> Map<String, Value> {
+    let mut data = Map::new();
+    data.insert("os".to_string(), Value::from(std::env::consts::OS));
+    data.insert("arch".to_string(), Value::from(std::env::consts::ARCH));
+    data.insert(
+        "watcher_version".to_string(),
+        Value::from(env!("CARGO_PKG_VERSION")),
+    );
+    data.insert(
+        "capture_mode".to_string(),
+        Value::from(capture_mode_name(capture_mode, false)),
+    );
+    data
+}
+
+/// Name of the capture mode, `listen` once a grab fell back to listen mode
+fn capture_mode_name(capture_mode: CaptureMode, fell_back: bool) -> &'static str {
+    if cfg!(feature = "mock_input") {
+        "mock"
+    } else if fell_back {
+        CaptureMode::Listen.as_str()
+    } else {
+        capture_mode.as_str()
+    }
+}
+
+/// A bucket of this watcher with [`bucket_metadata`], owned by `client`
+fn watcher_bucket(
+    client: &AwClient,
+    id: &str,
+    bucket_type: &str,
+    capture_mode: CaptureMode,
+) -> Bucket {
+    Bucket {
+        bid: None,
+        id: id.to_string(),
+        _type: bucket_type.to_string(),
+        client: client.name.clone(),
+        hostname: client.hostname.clone(),
+        created: None,
+        data: bucket_metadata(capture_mode),
+        metadata: BucketMetadata::default(),
+        events: None,
+        last_updated: None,
+    }
+}
+
+/// Event data with exactly the fields of the Python aw-watcher-input. All of
+/// them are always present, and the deltas and scroll distances are absolute
+/// sums like the ones it computes.
+fn python_compatible_data(data: &InputState) -> Map<String, Value> {
+    let mut data_map = Map::new();
+    data_map.insert("presses".to_string(), Value::from(data.presses));
+    data_map.insert("clicks".to_string(), Value::from(data.clicks));
+    data_map.insert("deltaX".to_string(), Value::from(data.delta_x));
+    data_map.insert("deltaY".to_string(), Value::from(data.delta_y));
+    data_map.insert(
+        "scrollX".to_string(),
+        Value::from(data.scroll_x.round() as u64),
+    );
+    data_map.insert(
+        "scrollY".to_string(),
+        Value::from(data.scroll_y.round() as u64),
+    );
+    data_map
+}
+
+/// Log levels accepted by --log-level
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for log::LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+/// Log line formats accepted by --log-format
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Collects the structured fields of a log record as JSON values
+struct JsonFields(Map<String, Value>);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for JsonFields {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        let value = if let Some(number) = value.to_u64() {
+            Value::from(number)
+        } else if let Some(number) = value.to_i64() {
+            Value::from(number)
+        } else if let Some(number) = value.to_f64() {
+            Value::from(number)
+        } else if let Some(flag) = value.to_bool() {
+            Value::from(flag)
+        } else {
+            Value::from(value.to_string())
+        };
+        self.0.insert(key.as_str().to_string(), value);
+        Ok(())
+    }
+}
+
+/// Write a log record as a single line of JSON, with its structured fields
+/// under `fields`
+fn write_json_log(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> io::Result<()> {
+    let mut line = Map::new();
+    line.insert(
+        "timestamp".to_string(),
+        Value::from(buf.timestamp().to_string()),
+    );
+    line.insert("level".to_string(), Value::from(record.level().to_string()));
+    line.insert("target".to_string(), Value::from(record.target()));
+    line.insert(
+        "message".to_string(),
+        Value::from(record.args().to_string()),
+    );
+    let mut fields = JsonFields(Map::new());
+    record.key_values().visit(&mut fields).ok();
+    if !fields.0.is_empty() {
+        line.insert("fields".to_string(), Value::Object(fields.0));
+    }
+    writeln!(buf, "{}", Value::Object(line))
+}
+
+/// Log writer that copies everything written to stderr into a file as well
+struct TeeWriter {
+    file: std::fs::File,
+}
+
+impl Write for TeeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stderr().write_all(buf)?;
+        self.file.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()?;
+        self.file.flush()
+    }
+}
+
+#[cfg(windows)]
+extern "system" {
+    fn FreeConsole() -> i32;
+}
+
+/// Whether the watcher runs on Windows without a console, so logs have to go
+/// to a file to be seen at all
+fn without_console(args: &Args) -> bool {
+    cfg!(windows) && (args.no_console || cfg!(feature = "windows_gui"))
+}
+
+/// Close the console window the watcher was started with. Writes to stdout and
+/// stderr are discarded from then on.
+fn detach_console() {
+    #[cfg(windows)]
+    // SAFETY: takes no arguments, and the standard streams ignore the handles
+    // it invalidates
+    unsafe {
+        FreeConsole();
+    }
+}
+
+/// Set up logging from the command line arguments.
+/// An explicit --log-level wins over RUST_LOG, which wins over --verbose.
+fn init_logging(args: &Args) {
+    let mut builder = match args.log_level {
+        Some(level) => {
+            let mut builder = env_logger::Builder::new();
+            builder.filter_level(level.into());
+            builder
+        }
+        None => {
+            let default_level = if args.verbose { "debug" } else { "info" };
+            env_logger::Builder::from_env(
+                env_logger::Env::default().default_filter_or(default_level),
+            )
+        }
+    };
+
+    if args.log_format == LogFormat::Json {
+        builder.format(write_json_log);
+    }
+
+    let log_file = args
+        .log_file
+        .clone()
+        .or_else(|| default_log_path().filter(|_| without_console(args)));
+    if let Some(path) = &log_file {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).ok();
+        }
+        // Append so logs from previous runs are kept across restarts
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => {
+                builder.target(env_logger::Target::Pipe(Box::new(TeeWriter { file })));
+            }
+            Err(e) => eprintln!("Error opening log file {}: {}", path.display(), e),
+        }
+    }
+
+    builder.init();
+}
+
+/// The settings that differ between two configurations, as `key: old -> new`
+fn changed_settings(old: &AppConfig, new: &AppConfig) -> Vec<String> {
+    let (Ok(Value::Object(old)), Ok(Value::Object(new))) =
+        (serde_json::to_value(old), serde_json::to_value(new))
+    else {
+        return Vec::new();
+    };
+    new.iter()
+        .filter(|(key, value)| old.get(key.as_str()) != Some(value))
+        .map(|(key, value)| {
+            let before = old.get(key.as_str()).unwrap_or(&Value::Null);
+            format!("{}: {} -> {}", key, before, value)
+        })
+        .collect()
+}
+
+/// The configuration with the command line overrides applied, as TOML. A
+/// comment above it names the config file, environment variables and flags
+/// the values came from.
+fn effective_config(mut config: AppConfig, args: &Args) -> Result<String, toml::ser::Error> {
+    let mut overrides = Vec::new();
+    let mut flag = |name: &str, passed: bool| {
+        if passed {
+            overrides.push(format!("--{}", name));
+        }
+    };
+    flag("poll-time", args.poll_time.is_some());
+    flag("host", args.host.is_some());
+    flag("port", args.port.is_some());
+    flag("scheme", args.scheme.is_some());
+    flag("ca-cert", args.ca_cert.is_some());
+    flag("hostname", args.hostname.is_some());
+    flag("bucket-id", args.bucket_id.is_some());
+    flag("client-id", args.client_id.is_some());
+    flag("afk", args.afk);
+    flag("output-file", args.output_file.is_some());
+    flag("dry-run", args.dry_run);
+    flag("no-server", args.no_server);
+
+    config.polling_interval = args.poll_time.unwrap_or(config.polling_interval);
+    config.host = args.host.clone().unwrap_or(config.host);
+    config.port = args.port.unwrap_or(config.port);
+    config.scheme = args.scheme.unwrap_or(config.scheme);
+    if let Some(path) = &args.ca_cert {
+        config.ca_cert = Some(path.display().to_string());
+    }
+    config.hostname = args.hostname.clone().or(config.hostname);
+    config.bucket_id = args.bucket_id.clone().or(config.bucket_id);
+    config.client_id = args.client_id.clone().or(config.client_id);
+    config.afk |= args.afk;
+    if let Some(path) = &args.output_file {
+        config.output_file = Some(path.display().to_string());
+    }
+    config.sinks = selected_sinks(
+        &config.sinks,
+        args.dry_run,
+        args.no_server,
+        args.output_file.is_some(),
+    );
+
+    let config_file = args.config.clone().or_else(default_config_path);
+    let mut environment: Vec<String> = std::env::vars()
+        .map(|(name, _)| name)
+        .filter(|name| name.starts_with(&format!("{}_", ENV_PREFIX)))
+        .collect();
+    environment.sort();
+    let list = |names: &[String]| {
+        if names.is_empty() {
+            "none".to_string()
+        } else {
+            names.join(", ")
+        }
+    };
+
+    Ok(format!(
+        "# Config file: {}\n# Environment: {}\n# Command line: {}\n\n{}",
+        config_file.map_or("none".to_string(), |path| path.display().to_string()),
+        list(&environment),
+        list(&overrides),
+        toml::to_string_pretty(&config)?
+    ))
+}
+
+/// Version with the commit and build date recorded by build.rs
+const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("GIT_HASH"),
+    ", built ",
+    env!("BUILD_DATE"),
+    ")"
+);
+
+/// Command line arguments for aw-watcher-input
+#[derive(Parser, Debug)]
+#[clap(author, version = LONG_VERSION, about = "ActivityWatch Input Watcher")]
+struct Args {
+    /// ActivityWatch server hostname, instead of host from the config file (default: localhost)
+    #[clap(long)]
+    host: Option<String>,
+
+    /// ActivityWatch server port, instead of port from the config file (default: 5600)
+    #[clap(long)]
+    port: Option<u16>,
+
+    /// Connect to the server over http or https, instead of scheme from the
+    /// config file (default: http)
+    #[clap(long, value_enum)]
+    scheme: Option<Scheme>,
+
+    /// PEM certificate to trust for https, such as the one of a self-signed
+    /// server, instead of ca_cert from the config file
+    #[clap(long)]
+    ca_cert: Option<PathBuf>,
+
+    /// Use testing mode (different bucket)
+    #[clap(long)]
+    testing: bool,
+
+    /// Override the polling interval from config (in seconds, may be fractional)
+    #[clap(long)]
+    poll_time: Option<f64>,
+
+    /// Also report AFK status to an aw-watcher-afk compatible bucket
+    #[clap(long)]
+    afk: bool,
+
+    /// Override the heartbeat pulsetime (in seconds)
+    #[clap(long)]
+    pulsetime: Option<f64>,
+
+    /// Enable debug logging, including a line for every heartbeat
+    #[clap(short, long)]
+    verbose: bool,
+
+    /// Set the log level, overriding RUST_LOG and --verbose
+    #[clap(long, value_enum)]
+    log_level: Option<LogLevel>,
+
+    /// Write log lines as plain text or as one JSON object each
+    #[clap(long, value_enum, default_value = "text")]
+    log_format: LogFormat,
+
+    /// Also write logs to this file, appending to it if it exists
+    #[clap(long)]
+    log_file: Option<PathBuf>,
+
+    /// On Windows, close the console window after starting and log to a file
+    #[clap(long)]
+    no_console: bool,
+
+    /// Print events to stdout as JSON instead of sending them to aw-server
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Send only the fields of the Python aw-watcher-input
+    #[clap(long)]
+    compat: bool,
+
+    /// Also append every input event to this file as a line of JSON, adding the jsonl sink
+    #[clap(long)]
+    output_file: Option<PathBuf>,
+
+    /// Don't connect to aw-server, only use the other sinks
+    #[clap(long)]
+    no_server: bool,
+
+    /// Keep unsent events in this SQLite database instead of in memory, so
+    /// they survive restarts
+    #[cfg(feature = "buffer_db")]
+    #[clap(long)]
+    buffer_db: Option<PathBuf>,
+
+    /// How to capture input: listen through the display server, or grab the
+    /// input devices in a build with unstable_grab
+    #[cfg(target_os = "linux")]
+    #[clap(long, value_parser = parse_capture_mode, default_value = "listen")]
+    capture_mode: CaptureMode,
+
+    /// Send a single heartbeat after one polling interval, then exit
+    #[clap(long)]
+    once: bool,
+
+    /// Run for a fixed time such as 30s, 10m or 2h, then shut down gracefully
+    #[clap(long, value_parser = parse_duration)]
+    duration: Option<Duration>,
+
+    /// Path to the config file, instead of the default location
+    #[clap(long, env = "AW_WATCHER_INPUT_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Hostname to use in bucket IDs instead of the detected one
+    #[clap(long)]
+    hostname: Option<String>,
+
+    /// Bucket ID to use instead of the hostname-derived one
+    #[clap(long)]
+    bucket_id: Option<String>,
+
+    /// Client name reported to aw-server
+    #[clap(long)]
+    client_id: Option<String>,
+
+    /// Check that aw-server is reachable and the buckets can be created, then exit
+    #[clap(long)]
+    check: bool,
+
+    /// Write a config file listing every setting with its default, then exit
+    #[clap(long)]
+    generate_config: bool,
+
+    /// Print the configuration resolved from the config file, environment and
+    /// command line as TOML, then exit
+    #[clap(long)]
+    print_config: bool,
+
+    /// Print only the version number, without build info, then exit
+    #[clap(long)]
+    version_short: bool,
+
+    /// On macOS, write a LaunchAgent that starts the watcher at login with the
+    /// other arguments given, then exit
+    #[clap(long)]
+    install_service: bool,
+
+    /// On macOS, remove the LaunchAgent written by --install-service, then exit
+    #[clap(long, conflicts_with = "install_service")]
+    uninstall_service: bool,
+
+    /// With --install-service, replace an existing LaunchAgent
+    #[clap(long, requires = "install_service")]
+    overwrite_service: bool,
+
+    /// Overwrite an existing config file with --generate-config, or start even
+    /// if another instance is running
+    #[clap(long)]
+    force: bool,
+
+    /// Serve Prometheus metrics on this port of localhost
+    #[cfg(feature = "metrics")]
+    #[clap(long)]
+    metrics_port: Option<u16>,
+}
+
+/// The sinks that write locally, in the order of `kinds`. Exits with an error
+/// if one of them can't be opened.
+fn local_sinks(
+    kinds: &[SinkKind],
+    args: &Args,
+    config: &AppConfig,
+    bucket_id: &str,
+    afk_bucket_id: &str,
+    daily_bucket_id: &str,
+) -> Vec<Box<dyn EventSink>> {
+    let mut sinks: Vec<Box<dyn EventSink>> = Vec::new();
+    for kind in kinds {
+        match kind {
+            SinkKind::AwServer => {}
+            SinkKind::Stdout => sinks.push(Box::new(sink::Stdout {
+                bucket_id: bucket_id.to_string(),
+                afk_bucket_id: afk_bucket_id.to_string(),
+                daily_bucket_id: daily_bucket_id.to_string(),
+            })),
+            SinkKind::Jsonl => {
+                let Some(path) = args
+                    .output_file
+                    .clone()
+                    .or_else(|| config.output_file.as_ref().map(PathBuf::from))
+                else {
+                    error!("The jsonl sink needs output_file in the config file or --output-file");
+                    std::process::exit(1);
+                };
+                match sink::JsonLines::open(&path) {
+                    Ok(output) => sinks.push(Box::new(output)),
+                    Err(e) => {
+                        error!("Error opening output file {}: {}", path.display(), e);
+                        std::process::exit(1);
+                    }
+                }
+                info!("Appending events to {}", path.display());
+            }
+            SinkKind::Sqlite => {
+                let Some(path) = config.sqlite_file.as_ref().map(PathBuf::from) else {
+                    error!("The sqlite sink needs sqlite_file in the config file");
+                    std::process::exit(1);
+                };
+                #[cfg(not(feature = "buffer_db"))]
+                {
+                    error!(
+                        "Logging events to {} needs a build with the buffer_db feature",
+                        path.display()
+                    );
+                    std::process::exit(1);
+                }
+                #[cfg(feature = "buffer_db")]
+                match buffer::EventLog::open(&path) {
+                    Ok(log) => sinks.push(Box::new(log)),
+                    Err(e) => {
+                        error!("Error opening SQLite database {}: {}", path.display(), e);
+                        std::process::exit(1);
+                    }
+                }
+                #[cfg(feature = "buffer_db")]
+                info!("Logging events to {}", path.display());
+            }
+        }
+    }
+    sinks
+}
+
+/// Where events that failed to send are kept to be replayed once the server
+/// is reachable: --buffer-db if given, memory otherwise
+fn open_backlog(args: &Args, config: &AppConfig) -> Backlog {
+    #[cfg(feature = "buffer_db")]
+    if let Some(path) = &args.buffer_db {
+        return match buffer::EventBuffer::open(path) {
+            Ok(buffer) => {
+                info!("Buffering unsent events in {}", path.display());
+                Backlog::Database(buffer)
+            }
+            Err(e) => {
+                error!("Error opening buffer database {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        };
+    }
+    #[cfg(not(feature = "buffer_db"))]
+    let _ = args;
+    Backlog::Memory(queue::EventQueue::new(config.max_queued_events))
+}
+
+fn main() {
+    // Parse command line arguments
+    let args = Args::parse();
+
+    if args.version_short {
+        println!("{}", env!("CARGO_PKG_VERSION"));
+        return;
+    }
+
+    // Set up logging before anything else gets reported
+    init_logging(&args);
+
+    if args.no_console {
+        if cfg!(windows) {
+            detach_console();
+        } else {
+            warn!("--no-console only has an effect on Windows");
+        }
+    }
+
+    if args.generate_config {
+        let Some(path) = args.config.clone().or_else(default_config_path) else {
+            error!("Could not determine the config directory, pass --config <path>");
+            std::process::exit(1);
+        };
+        match generate_config(&path, args.force) {
+            Ok(()) => {
+                info!("Wrote config template to {}", path.display());
+                return;
+            }
+            Err(e) => {
+                error!("Error writing config template to {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if args.install_service || args.uninstall_service {
+        manage_service(&args);
+        return;
+    }
+
+    // Load configuration
+    let mut config = match AppConfig::new(args.config.clone()) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Error loading configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if args.print_config {
+        match effective_config(config, &args) {
+            Ok(effective) => {
+                print!("{}", effective);
+                return;
+            }
+            Err(e) => {
+                error!("Error printing the configuration: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Use poll_time from args if provided, otherwise from config
+    let mut polling_interval = args.poll_time.unwrap_or(config.polling_interval);
+    if !polling_interval.is_finite() || polling_interval <= 0.0 {
+        error!("The polling interval must be a number greater than 0");
+        std::process::exit(1);
+    }
+
+    // Flags that were passed win over the config file
+    let host = args.host.clone().unwrap_or_else(|| config.host.clone());
+    let port = args.port.unwrap_or(config.port);
+    if let Err(e) = validate_host(&host) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    let scheme = args.scheme.unwrap_or(config.scheme);
+    let server_url = server::base_url(scheme, &host, port);
+    let ca_cert_path = args
+        .ca_cert
+        .clone()
+        .or_else(|| config.ca_cert.as_ref().map(PathBuf::from));
+    if ca_cert_path.is_some() && scheme != Scheme::Https {
+        error!("A CA certificate is only used with --scheme https");
+        std::process::exit(1);
+    }
+    let ca_cert = ca_cert_path.map(|path| {
+        server::load_certificate(&path).unwrap_or_else(|e| {
+            error!("Error loading the CA certificate {}: {}", path.display(), e);
+            std::process::exit(1);
+        })
+    });
+
+    #[cfg(target_os = "linux")]
+    let capture_mode = args.capture_mode;
+    #[cfg(not(target_os = "linux"))]
+    let capture_mode = CaptureMode::Listen;
+    if capture_mode == CaptureMode::Grab && !cfg!(feature = "unstable_grab") {
+        error!("Grab mode needs a build with --features unstable_grab, run with --capture-mode listen or rebuild");
+        std::process::exit(1);
+    }
+
+    // Heartbeats within this many seconds of each other are merged by the server,
+    // recomputed whenever the interval changes
+    let mut pulsetime = args
+        .pulsetime
+        .unwrap_or(polling_interval + config.pulsetime_margin);
+
+    // Get hostname and create bucket ID with hostname appended. An override
+    // is normalized the same way, and the system isn't asked at all.
+    let hostname = match args.hostname.clone().or(config.hostname.clone()) {
+        Some(hostname) => sanitize_hostname(&hostname),
+        None => match get_hostname() {
+            Ok(name) => {
+                // Bytes that aren't UTF-8 become U+FFFD and are then replaced
+                // like any other character
+                if name.to_str().is_none() {
+                    warn!(
+                        "Hostname {:?} is not valid UTF-8, replacing the invalid characters",
+                        name
+                    );
+                }
+                sanitize_hostname(&name.to_string_lossy())
+            }
+            Err(_) => "unknown-host".to_string(),
+        },
+    };
+
+    // An explicit bucket ID replaces the derived one entirely, otherwise
+    // add testing suffix if in testing mode
+    let bucket_id = if let Some(bucket_id) = args.bucket_id.clone().or(config.bucket_id.clone()) {
+        bucket_id
+    } else if args.testing {
+        format!("aw-watcher-input-testing_{}", hostname)
+    } else {
+        format!("aw-watcher-input_{}", hostname)
+    };
+    let client_id = args
+        .client_id
+        .clone()
+        .or(config.client_id.clone())
+        .unwrap_or_else(|| "aw-watcher-input".to_string());
+    let event_type = "os.hid.input";
+
+    // The AFK bucket follows the naming used by aw-watcher-afk
+    let afk_enabled = args.afk || config.afk;
+    let afk_bucket_id = if args.testing {
+        format!("aw-watcher-afk-testing_{}", hostname)
+    } else {
+        format!("aw-watcher-afk_{}", hostname)
+    };
+    let daily_bucket_id = if args.testing {
+        format!("aw-watcher-input-daily-testing_{}", hostname)
+    } else {
+        format!("aw-watcher-input-daily_{}", hostname)
+    };
+    let daily_event_type = "os.hid.input.daily";
+
+    info!(
+        "Starting aw-watcher-input-rs {} with polling interval of {} seconds",
+        LONG_VERSION, polling_interval
+    );
+    info!("Using bucket ID: {}", bucket_id);
+    if afk_enabled {
+        info!("Reporting AFK status to bucket ID: {}", afk_bucket_id);
+    }
+    if config.daily_summary {
+        info!("Sending daily summaries to bucket ID: {}", daily_bucket_id);
+    }
+    info!("Connecting to aw-server at {}", server_url);
+    if args.testing {
+        info!("Running in testing mode");
+    }
+
+    if args.check {
+        let result = AwClient::new(&host, port, &client_id)
+            .map_err(|e| format!("could not create a client for {}: {}", server_url, e))
+            .and_then(|client| {
+                let bucket = |id: &str, bucket_type: &str| {
+                    watcher_bucket(&client, id, bucket_type, capture_mode)
+                };
+                let mut buckets = vec![bucket(&bucket_id, event_type)];
+                if afk_enabled {
+                    buckets.push(bucket(&afk_bucket_id, "afkstatus"));
+                }
+                if config.daily_summary {
+                    buckets.push(bucket(&daily_bucket_id, daily_event_type));
+                }
+                let api = server::ServerApi::new(
+                    server_url.clone(),
+                    ca_cert.clone(),
+                    config.sender_settings(),
+                )?;
+                check_server(&api, &server_url, &buckets)
+            });
+        match result {
+            Ok(()) => {
+                println!("Check passed");
+                return;
+            }
+            Err(e) => {
+                eprintln!("Check failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Two watchers feeding the same bucket would double-count all input. The
+    // lock is held until the process exits, dry runs never touch the bucket.
+    let _instance_lock = if config.single_instance && !args.dry_run && !args.force {
+        match lock_path(args.config.as_deref(), &bucket_id).map(|path| lock_instance(&path)) {
+            Some(Ok(file)) => Some(file),
+            Some(Err(e)) => {
+                error!("Not starting: {}", e);
+                std::process::exit(1);
+            }
+            None => {
+                warn!("Could not determine the config directory, running without a lock file");
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // Set up Ctrl+C handler
+    RUNNING.store(true, Ordering::SeqCst);
+    ctrlc::set_handler(move || {
+        // A second Ctrl+C forces the exit if the final heartbeat is stuck
+        if !RUNNING.swap(false, Ordering::SeqCst) {
+            std::process::exit(1);
+        }
+        info!("Received Ctrl+C, shutting down gracefully...");
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    #[cfg(unix)]
+    spawn_signal_thread();
+
+    #[cfg(feature = "metrics")]
+    if let Some(port) = args.metrics_port {
+        if let Err(e) = metrics::spawn_server(port) {
+            error!("Error starting the metrics server on port {}: {}", port, e);
+            std::process::exit(1);
+        }
+        info!("Serving metrics on http://127.0.0.1:{}/metrics", port);
+    }
+
+    let sink_kinds = selected_sinks(
+        &config.sinks,
+        args.dry_run,
+        args.no_server,
+        args.output_file.is_some(),
+    );
+    if sink_kinds.is_empty() {
+        error!("No sinks left to send heartbeats to, check sinks in the config file");
+        std::process::exit(1);
+    }
+    if args.dry_run {
+        info!("Dry run, events are printed instead of sent to aw-server");
+    } else if args.no_server {
+        info!("Not connecting to aw-server");
+    }
+
+    // Open the local sinks before connecting, so a bad path is reported right away
+    let mut sinks = local_sinks(
+        &sink_kinds,
+        &args,
+        &config,
+        &bucket_id,
+        &afk_bucket_id,
+        &daily_bucket_id,
+    );
+
+    if sink_kinds.contains(&SinkKind::AwServer) {
+        // The server may still be starting up, e.g. when the watcher is launched at login
+        let retry_interval = Duration::from_secs(config.startup_retry_interval);
+        let client = retry_until_ready(
+            "create aw-server client",
+            retry_interval,
+            config.startup_retries,
+            || AwClient::new(&host, port, &client_id),
+        )
+        .unwrap_or_else(|e| {
+            error!("Error creating aw-server client: {}", e);
+            std::process::exit(1);
+        });
+
+        // Buckets are created and heartbeats sent through a client that reports
+        // error statuses, which aw-client-rust treats as successes
+        let api = server::ServerApi::new(server_url.clone(), ca_cert, config.sender_settings())
+            .unwrap_or_else(|e| {
+                error!("Error creating aw-server client: {}", e);
+                std::process::exit(1);
+            });
+
+        // Create or get the buckets, kept by the sink to create them again if
+        // they are deleted on the server
+        let mut buckets = vec![(
+            "input",
+            watcher_bucket(&client, &bucket_id, event_type, capture_mode),
+        )];
+        if afk_enabled {
+            buckets.push((
+                "AFK",
+                watcher_bucket(&client, &afk_bucket_id, "afkstatus", capture_mode),
+            ));
+        }
+        if config.daily_summary {
+            buckets.push((
+                "daily",
+                watcher_bucket(&client, &daily_bucket_id, daily_event_type, capture_mode),
+            ));
+        }
+        let buckets = buckets
+            .into_iter()
+            .map(|(name, bucket)| {
+                retry_until_ready(
+                    &format!("create {} bucket", name),
+                    retry_interval,
+                    config.startup_retries,
+                    || api.create_bucket(&bucket),
+                )
+                .unwrap_or_else(|e| {
+                    error!("Failed to create {} bucket: {}", name, e);
+                    std::process::exit(1);
+                });
+                bucket
+            })
+            .collect();
+
+        let backlog = open_backlog(&args, &config);
+        sinks.push(Box::new(sink::AwServer {
+            api,
+            bucket_id: bucket_id.clone(),
+            afk_bucket_id: afk_bucket_id.clone(),
+            daily_bucket_id: daily_bucket_id.clone(),
+            recreation: sink::BucketRecreation::new(buckets),
+            settings: config.sender_settings(),
+            backlog,
+            daily_backlog: queue::EventQueue::new(sink::MAX_QUEUED_DAILY_SUMMARIES),
+        }));
+    }
+    debug!(
+        "Sending heartbeats to {}",
+        sink_kinds
+            .iter()
+            .map(SinkKind::as_str)
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    // Heartbeats are handed to the sender thread, so a slow server never
+    // delays the next snapshot
+    let (outgoing, sender_thread) = spawn_sender(sinks, config.max_queued_events);
+
+    // Markers are sent without pulsetime, so they never merge with input events
+    if config.session_events {
+        outgoing.send(Outgoing::Input(session_event("session_start"), 0.0));
+    }
+
+    // Whether the previous interval was left out because it had no input
+    let mut skipped_last = false;
+
+    let tracking = Tracking {
+        keyboard: config.track_keyboard,
+        mouse_buttons: config.track_mouse_buttons,
+        mouse_move: config.track_mouse_move,
+        scroll: config.track_scroll,
+        activity_only: config.privacy_mode,
+    };
+    // The listener keeps recording the same way after a reload
+    let privacy_mode = tracking.activity_only;
+
+    // Setup shared state for input monitoring
+    let input_state = Arc::new(Mutex::new(InputState::new(config.input_options())));
+
+    // All-time totals, left untouched when the file can't be read so that a
+    // damaged file is never overwritten with zeros
+    let totals_path = config
+        .lifetime_totals
+        .then(|| totals_path(args.config.as_deref()))
+        .flatten();
+    let lifetime_totals =
+        totals_path
+            .as_deref()
+            .and_then(|path| match LifetimeTotals::load(path) {
+                Ok(totals) => Some(Arc::new(Mutex::new(totals))),
+                Err(e) => {
+                    warn!(
+                        "Error reading lifetime totals from {}, not updating them: {}",
+                        path.display(),
+                        e
+                    );
+                    None
+                }
+            });
+    // A day recorded by an earlier run continues if it is still today, an
+    // older one is sent with the first interval
+    if config.daily_summary {
+        let today = Local::now().format("%Y-%m-%d").to_string();
+        let resumed = lifetime_totals
+            .as_ref()
+            .and_then(|totals| totals.lock().ok()?.today.clone())
+            .filter(|day| day.date == today);
+        match resumed {
+            Some(day) => info!(
+                "Resuming the daily summary of {} at {} presses and {} clicks",
+                day.date, day.presses, day.clicks
+            ),
+            None => info!("Starting a fresh daily summary for {}", today),
+        }
+    }
+
+    // The status socket only ever answers with the current counters
+    let status_socket = config.status_socket.then(|| {
+        config
+            .status_socket_path
+            .clone()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| default_status_socket_path(&bucket_id))
+    });
+    #[cfg(unix)]
+    if let Some(path) = &status_socket {
+        if let Err(e) = status::spawn_server(
+            path,
+            Arc::clone(&input_state),
+            lifetime_totals.clone(),
+            Instant::now(),
+        ) {
+            error!("Error opening the status socket {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+        info!("Serving status on {}", path.display());
+    }
+    #[cfg(not(unix))]
+    if status_socket.is_some() {
+        warn!("The status socket is only supported on Unix");
+    }
+
+    let listener_options = ListenerOptions {
+        capture_mode,
+        retries: config.listener_retries,
+        grab_fallback: config.grab_fallback,
+    };
+    let start_listener =
+        || create_input_listener_thread(Arc::clone(&input_state), tracking, listener_options);
+
+    // On macOS nothing is captured until Input Monitoring is granted, so hold
+    // off on starting the listener and check again every interval
+    let mut awaiting_permission = !input_monitoring_allowed();
+    let mut listener_thread = if awaiting_permission {
+        warn!(
+            "Input Monitoring permission is missing, no input will be recorded until it is granted"
+        );
+        warn!("Open System Settings → Privacy & Security → Input Monitoring and enable aw-watcher-input-rs, or the terminal running it");
+        warn!("The watcher keeps running and starts recording once the permission is granted");
+        request_input_monitoring();
+        None
+    } else {
+        info!("Input monitoring thread started");
+        Some(start_listener())
+    };
+    // Set when input can't be captured any more, the watcher then exits with an error
+    let mut listener_failed = false;
+
+    match capture_mode {
+        CaptureMode::Listen => info!("Input detection is now active using rdev listen mode"),
+        CaptureMode::Grab => {
+            info!("Input detection is now active using rdev grab mode (Linux)");
+            info!("NOTE: This requires your user to be in the 'input' group or to run as root");
+            info!("To add your user to the input group: sudo usermod -a -G input $USER");
+            info!("On some distributions, you may need to use the 'plugdev' group instead");
+            info!("You must log out and back in for group changes to take effect");
+        }
+    }
+
+    // How long a Wayland session went without any input, used to point out
+    // once that capture is probably not working
+    #[cfg(target_os = "linux")]
+    let mut wayland_idle = if capture_mode == CaptureMode::Listen && is_wayland_session() {
+        warn!("Wayland session detected, global input capture may not work in listen mode");
+        warn!("Only input sent to XWayland windows might be recorded");
+        warn!("{}", grab_hint());
+        Some(Duration::ZERO)
+    } else {
+        None
+    };
+
+    info!("Press Ctrl+C to exit");
+
+    // Start of the interval whose input is currently being accumulated
+    let mut interval_start = Utc::now();
+    let mut interval_instant = Instant::now();
+    let mut interval = Duration::from_secs_f64(polling_interval);
+    // Ticks are scheduled on a fixed grid from startup, so the time spent
+    // sending doesn't accumulate into drift
+    let mut next_tick = interval_instant + interval;
+    let deadline = args.duration.map(|duration| interval_instant + duration);
+    // Intervals in a row without input, used to switch to idle_polling_interval
+    let mut empty_intervals: u32 = 0;
+    let mut idle_polling = false;
+    // Power source, only read while battery_polling_interval is set
+    let power = power::PowerSource::open();
+    let mut quiet_windows = config.quiet_windows();
+    let mut in_quiet_hours = false;
+    let mut on_battery = false;
+    // Recent intervals for the window_* rates
+    let mut window = RollingWindow::new(Duration::from_secs_f64(config.aggregation_window));
+    // Overrunning cycles in a row, and the interval raised to fit them
+    let mut overruns = OverrunTracker::default();
+    let mut min_interval = 0.0;
+
+    // The buckets exist and the listener runs, or waits for its permission
+    let mut notifier = systemd::Notifier::connect();
+    notifier.ready();
+
+    // Main polling loop
+    loop {
+        // The watchdog is pinged from the sleep below as well, so it only
+        // times out when the loop itself hangs
+        notifier.watchdog();
+        // Sleep in smaller steps until the interval is over to be more responsive to shutdown signals
+        // Wake up at the --duration deadline as well, so the final partial interval
+        // is flushed on time
+        // The jitter only delays the wake-up, the interval still ends on the
+        // grid tick, so it never adds up to drift. It stays well below the
+        // interval so no tick gets skipped.
+        let wake_tick =
+            next_tick + jitter(Duration::from_millis(config.jitter_ms).min(interval / 2));
+        let wake_at = deadline.map_or(wake_tick, |deadline| deadline.min(wake_tick));
+        while RUNNING.load(Ordering::SeqCst) && Instant::now() < wake_at {
+            // While polling slowly, end the interval on the first input so the
+            // fast interval takes over right away
+            if idle_polling && input_state.lock().is_ok_and(|state| !state.is_empty()) {
+                break;
+            }
+            if FLUSH_REQUESTED.load(Ordering::SeqCst) {
+                break;
+            }
+            notifier.watchdog();
+            let remaining = wake_at.saturating_duration_since(Instant::now());
+            sleep(remaining.min(Duration::from_millis(100)));
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline)
+            && RUNNING.swap(false, Ordering::SeqCst)
+        {
+            info!("Run duration elapsed, shutting down gracefully...");
+        }
+
+        if awaiting_permission && input_monitoring_allowed() {
+            info!("Input Monitoring permission granted, starting input capture");
+            listener_thread = Some(start_listener());
+            awaiting_permission = false;
+        }
+
+        // The listener only returns once it gave up on capturing input. Flush
+        // what was collected and exit instead of reporting no input forever.
+        if listener_thread
+            .as_ref()
+            .is_some_and(|thread| thread.is_finished())
+        {
+            let error = match listener_thread.take().map(|thread| thread.join()) {
+                Some(Ok(Err(e))) => Some(e),
+                Some(Err(_)) => Some("the listener thread panicked".to_string()),
+                _ => None,
+            };
+            if let Some(e) = error {
+                error!("Input capture failed, shutting down: {}", e);
+                listener_failed = true;
+                RUNNING.store(false, Ordering::SeqCst);
+            }
+        }
+
+        // On shutdown the interval is cut short and whatever was collected so far
+        // gets flushed. With --once the first interval goes the same way.
+        let shutting_down = !RUNNING.load(Ordering::SeqCst) || args.once;
+        // A flush ends the interval early like a shutdown, but keeps the grid
+        let flushing = FLUSH_REQUESTED.swap(false, Ordering::SeqCst);
+
+        // Record the start time of this iteration
+        let loop_start = Instant::now();
+        let snapshot_time = Utc::now();
+        let timestamp = interval_start;
+        let interval_end = if shutting_down {
+            loop_start
+        } else if loop_start < next_tick {
+            // Cut short by input while polling slowly, or by a flush
+            if flushing {
+                debug!("Flushing the interval so far on request");
+            } else {
+                next_tick = loop_start + interval;
+            }
+            loop_start
+        } else {
+            let tick = last_tick(next_tick, interval, loop_start);
+            next_tick = tick + interval;
+            tick
+        };
+        // Measured rather than assumed to be polling_interval, so an interval
+        // stretched by an overrun still tiles with its neighbours
+        let duration = TimeDelta::from_std(interval_end.duration_since(interval_instant))
+            .unwrap_or(TimeDelta::zero());
+        let monotonic_elapsed = loop_start.duration_since(interval_instant);
+        interval_start = timestamp + duration;
+        interval_instant = interval_end;
+
+        // The sleep above runs on the monotonic clock, so a wall-clock jump far
+        // beyond the interval means the system was suspended in between. The
+        // input around it doesn't belong to a single interval, so drop it
+        // instead of reporting the whole sleep as one active interval.
+        let wall_elapsed = (snapshot_time - timestamp).to_std().unwrap_or_default();
+        let expected = duration.to_std().unwrap_or_default();
+        if config.resume_threshold > 0
+            && wall_elapsed > expected + Duration::from_secs(config.resume_threshold)
+        {
+            info!(
+                "Detected system resume after {:?}, skipping the sleep gap",
+                wall_elapsed
+            );
+            if let Ok(mut state_guard) = input_state.lock() {
+                state_guard.reset();
+            }
+            // Start a fresh AFK event rather than extending one across the gap
+            outgoing.send(Outgoing::AfkGap);
+            // Re-anchor the grid, since wall-clock time moved on during the sleep
+            interval_start = snapshot_time;
+            interval_instant = loop_start;
+            next_tick = loop_start + interval;
+            if shutting_down {
+                break;
+            }
+            continue;
+        }
+
+        // Smaller wall-clock adjustments, by NTP or by hand, and backward jumps
+        // leave this interval's monotonic duration alone. The next interval
+        // starts on the adjusted clock, so the timeline doesn't drift from it.
+        if let Some(jump) = clock_jump(snapshot_time - timestamp, monotonic_elapsed) {
+            let seconds = jump.num_milliseconds() as f64 / 1000.0;
+            if seconds < 0.0 {
+                warn!(
+                    "The wall clock moved back by {:.1}s, events from now on may overlap earlier ones",
+                    -seconds
+                );
+            } else {
+                info!("The wall clock moved forward by {:.1}s", seconds);
+            }
+            let overrun = TimeDelta::from_std(loop_start.duration_since(interval_end))
+                .unwrap_or(TimeDelta::zero());
+            interval_start = snapshot_time - overrun;
+        }
+
+        // While paused by quiet hours or a signal, the listener only keeps the
+        // activity times fresh, and an interval overlapping the pause is not
+        // sent at all
+        let was_paused = PAUSED.load(Ordering::Relaxed);
+        let now = Local::now();
+        let minute = now.hour() * 60 + now.minute();
+        let quiet_now = quiet_windows.iter().any(|window| window.contains(minute));
+        if quiet_now != in_quiet_hours {
+            in_quiet_hours = quiet_now;
+            if quiet_now {
+                info!("Quiet hours started, pausing tracking");
+            } else {
+                info!("Quiet hours are over");
+            }
+        }
+        let paused_now = quiet_now || PAUSE_REQUESTED.load(Ordering::SeqCst);
+        if paused_now != was_paused {
+            PAUSED.store(paused_now, Ordering::Relaxed);
+            metrics::set(&metrics::PAUSED, paused_now as u64);
+            if !paused_now {
+                info!("Resuming tracking");
+                // The AFK status starts over after the pause
+                outgoing.send(Outgoing::AfkGap);
+            }
+        }
+        let quiet = was_paused || paused_now;
+
+        // Get current input state and reset counters in a single swap under the lock
+        let data = match input_state.lock() {
+            Ok(mut state_guard) => state_guard.take(),
+            // If we can't lock the state, use default values
+            Err(_) => InputState::default(),
+        };
+        empty_intervals = if data.is_empty() {
+            empty_intervals.saturating_add(1)
+        } else {
+            0
+        };
+        metrics::add(&metrics::PRESSES, data.presses);
+        metrics::add(&metrics::CLICKS, data.clicks);
+        if let (Some(totals), Some(path)) = (&lifetime_totals, &totals_path) {
+            if let Ok(mut totals) = totals.lock() {
+                // The interval counts towards the day it ends in
+                let finished = totals.roll_over(&now.format("%Y-%m-%d").to_string());
+                if let Some(day) = finished.as_ref().filter(|_| config.daily_summary) {
+                    match daily_event(day) {
+                        Some(event) => {
+                            info!("Sending the daily summary of {}", day.date);
+                            outgoing.send(Outgoing::Daily(event));
+                        }
+                        None => warn!("Not sending the daily summary of invalid date {}", day.date),
+                    }
+                }
+                if !data.is_empty() {
+                    totals.add(&data);
+                }
+                if finished.is_some() || !data.is_empty() {
+                    if let Err(e) = totals.save(path) {
+                        warn!("Error saving lifetime totals to {}: {}", path.display(), e);
+                    }
+                }
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(idle) = wayland_idle.as_mut() {
+            *idle = if data.is_empty() {
+                *idle + interval
+            } else {
+                Duration::ZERO
+            };
+            if *idle >= WAYLAND_IDLE_WARNING {
+                warn!(
+                    "No input recorded for {:?} on Wayland, rdev can likely not capture global input in this session",
+                    idle
+                );
+                warn!(
+                    "{}, with your user in the 'input' group, to fix this",
+                    grab_hint()
+                );
+                wayland_idle = None;
+            }
+        }
+
+        // Derive AFK status from the snapshot, since the reset above only
+        // carries the activity times over and the listener may update them at any time
+        let seconds_since_keyboard = data.last_keyboard_activity.elapsed().as_secs_f64();
+        let seconds_since_mouse = data.last_mouse_activity.elapsed().as_secs_f64();
+        let seconds_since_activity = match config.afk_input {
+            AfkInput::Any => data.last_activity.elapsed().as_secs_f64(),
+            AfkInput::Keyboard => seconds_since_keyboard,
+            AfkInput::Mouse => seconds_since_mouse,
+        };
+        let afk = seconds_since_activity >= config.afk_threshold as f64;
+
+        // Create event data
+        let mut data_map = Map::new();
+        if tracking.keyboard {
+            data_map.insert("presses".to_string(), Value::Number(data.presses.into()));
+            data_map.insert(
+                "corrections".to_string(),
+                Value::Number(data.corrections.into()),
+            );
+            data_map.insert("submits".to_string(), Value::Number(data.submits.into()));
+            data_map.insert(
+                "ctrl_presses".to_string(),
+                Value::Number(data.ctrl_presses.into()),
+            );
+            data_map.insert(
+                "alt_presses".to_string(),
+                Value::Number(data.alt_presses.into()),
+            );
+            data_map.insert(
+                "shift_presses".to_string(),
+                Value::Number(data.shift_presses.into()),
+            );
+            data_map.insert(
+                "meta_presses".to_string(),
+                Value::Number(data.meta_presses.into()),
+            );
+            data_map.insert(
+                "lock_toggles".to_string(),
+                Value::Number(data.lock_toggles.into()),
+            );
+            data_map.insert(
+                "presses_per_minute".to_string(),
+                Value::from(per_minute(data.presses, duration)),
+            );
+            data_map.insert(
+                "wpm".to_string(),
+                data.wpm().map_or(Value::Null, Value::from),
+            );
+            data_map.insert(
+                "key_hold_ms".to_string(),
+                Value::from(data.key_hold.as_millis() as u64),
+            );
+        }
+        if tracking.mouse_buttons {
+            data_map.insert("clicks".to_string(), Value::Number(data.clicks.into()));
+            data_map.insert(
+                "clicks_per_minute".to_string(),
+                Value::from(per_minute(data.clicks, duration)),
+            );
+            data_map.insert(
+                "double_clicks".to_string(),
+                Value::Number(data.double_clicks.into()),
+            );
+            data_map.insert(
+                "button_hold_ms".to_string(),
+                Value::from(data.button_hold.as_millis() as u64),
+            );
+            data_map.insert(
+                "clicksLeft".to_string(),
+                Value::Number(data.clicks_left.into()),
+            );
+            data_map.insert(
+                "clicksRight".to_string(),
+                Value::Number(data.clicks_right.into()),
+            );
+            data_map.insert(
+                "clicksMiddle".to_string(),
+                Value::Number(data.clicks_middle.into()),
+            );
+            data_map.insert(
+                "clicksOther".to_string(),
+                Value::Number(data.clicks_other.into()),
+            );
+        }
+        if tracking.mouse_move {
+            data_map.insert("deltaX".to_string(), Value::Number(data.delta_x.into()));
+            data_map.insert("deltaY".to_string(), Value::Number(data.delta_y.into()));
+            data_map.insert("distance".to_string(), Value::from(data.distance));
+            let speed = |speed: Option<f64>| speed.map_or(Value::Null, Value::from);
+            data_map.insert("mouse_speed_avg".to_string(), speed(data.mouse_speed_avg()));
+            data_map.insert("mouse_speed_max".to_string(), speed(data.mouse_speed_max));
+            data_map.insert("mouse_speed_min".to_string(), speed(data.mouse_speed_min));
+        }
+        if tracking.mouse_buttons && tracking.mouse_move {
+            data_map.insert("drags".to_string(), Value::Number(data.drags.into()));
+            data_map.insert("drag_distance".to_string(), Value::from(data.drag_distance));
+        }
+        if tracking.scroll {
+            // Scaling leaves fractions of a notch, report whole ones
+            let notches = |scroll: f64| Value::from(scroll.round() as u64);
+            data_map.insert("scrollX".to_string(), notches(data.scroll_x));
+            data_map.insert("scrollY".to_string(), notches(data.scroll_y));
+            data_map.insert("scrollUp".to_string(), notches(data.scroll_up));
+            data_map.insert("scrollDown".to_string(), notches(data.scroll_down));
+            data_map.insert("scrollLeft".to_string(), notches(data.scroll_left));
+            data_map.insert("scrollRight".to_string(), notches(data.scroll_right));
+        }
+        // Slices are whole seconds, so they can add up to more than a sub-second interval
+        let active_time =
+            (data.active_seconds as f64).min(duration.num_milliseconds() as f64 / 1000.0);
+        data_map.insert("active_time".to_string(), Value::from(active_time));
+        data_map.insert("bursts".to_string(), Value::Number(data.bursts.into()));
+        if tracking.keyboard && (tracking.mouse_buttons || tracking.mouse_move || tracking.scroll) {
+            data_map.insert(
+                "kbd_mouse_ratio".to_string(),
+                data.keyboard_mouse_ratio().map_or(Value::Null, Value::from),
+            );
+        }
+        if config.aggregation_window > 0.0 {
+            window.push(
+                duration.to_std().unwrap_or_default(),
+                data.presses,
+                data.clicks,
+                active_time,
+            );
+            let stats = window.stats();
+            data_map.insert("window_seconds".to_string(), Value::from(stats.seconds));
+            if tracking.keyboard {
+                data_map.insert(
+                    "window_presses_per_minute".to_string(),
+                    Value::from(stats.presses_per_minute),
+                );
+                data_map.insert("window_wpm".to_string(), Value::from(stats.wpm));
+            }
+            if tracking.mouse_buttons {
+                data_map.insert(
+                    "window_clicks_per_minute".to_string(),
+                    Value::from(stats.clicks_per_minute),
+                );
+            }
+            data_map.insert(
+                "window_active_time".to_string(),
+                Value::from(stats.active_time),
+            );
+        }
+        if config.report_capture_mode {
+            let fell_back = GRAB_FELL_BACK.load(Ordering::SeqCst);
+            data_map.insert(
+                "capture_mode".to_string(),
+                Value::from(capture_mode_name(capture_mode, fell_back)),
+            );
+        }
+        data_map.insert("afk".to_string(), Value::Bool(afk));
+        data_map.insert(
+            "seconds_since_activity".to_string(),
+            Value::from(seconds_since_activity),
+        );
+        data_map.insert(
+            "seconds_since_keyboard_activity".to_string(),
+            Value::from(seconds_since_keyboard),
+        );
+        data_map.insert(
+            "seconds_since_mouse_activity".to_string(),
+            Value::from(seconds_since_mouse),
+        );
+
+        if args.compat {
+            data_map = python_compatible_data(&data);
+        }
+
+        // Privacy mode reports only whether there was input at all
+        if privacy_mode {
+            data_map.clear();
+            data_map.insert("active".to_string(), Value::Bool(!data.is_empty()));
+        }
+
+        let event = Event {
+            id: None,
+            timestamp,
+            duration,
+            data: data_map,
+        };
+
+        // Debug output, with the counts as structured fields for --log-format json
+        debug!(
+            presses = data.presses,
+            clicks = data.clicks,
+            delta_x = data.delta_x,
+            delta_y = data.delta_y,
+            scroll_x = data.scroll_x,
+            scroll_y = data.scroll_y,
+            distance = data.distance;
+            "Heartbeat: presses={}, clicks={}, deltaX={}, deltaY={}, scrollX={:.1}, scrollY={:.1}, distance={:.1}",
+            data.presses,
+            data.clicks,
+            data.delta_x,
+            data.delta_y,
+            data.scroll_x,
+            data.scroll_y,
+            data.distance
+        );
+
+        // With skip_empty, idle intervals are left out of the bucket. The event
+        // after such a gap is sent without pulsetime, so it can't be merged
+        // into the one before the gap.
+        let skip = quiet || (config.skip_empty && data.is_empty());
+        let event_pulsetime = if skipped_last { 0.0 } else { pulsetime };
+        if quiet {
+            debug!("Skipping heartbeat while tracking is paused");
+        } else if skip {
+            debug!("Skipping heartbeat for an interval without input");
+        } else {
+            outgoing.send(Outgoing::Input(event, event_pulsetime));
+        }
+        skipped_last = skip;
+
+        if afk_enabled && !quiet {
+            // Like aw-watcher-afk, an AFK period starts at the last input
+            let (afk_timestamp, afk_duration) = if afk {
+                let since = TimeDelta::milliseconds((seconds_since_activity * 1000.0) as i64);
+                (snapshot_time - since, since)
+            } else {
+                (timestamp, duration)
+            };
+
+            let mut afk_data = Map::new();
+            let status = if afk { "afk" } else { "not-afk" };
+            afk_data.insert("status".to_string(), Value::String(status.to_string()));
+
+            let afk_event = Event {
+                id: None,
+                timestamp: afk_timestamp,
+                duration: afk_duration,
+                data: afk_data,
+            };
+
+            outgoing.send(Outgoing::Afk {
+                event: afk_event,
+                afk,
+                pulsetime: config.afk_threshold as f64 + interval.as_secs_f64(),
+            });
+        }
+
+        if shutting_down {
+            break;
+        }
+
+        // If taking the snapshot took longer than polling_interval, the missed ticks are
+        // skipped on the next iteration. A sustained overrun means the interval is
+        // too short for this machine, which is logged once per streak instead of
+        // on every cycle.
+        let elapsed = loop_start.elapsed();
+        if elapsed >= interval {
+            debug!(
+                "Operations took longer than polling interval ({:?} > {:?})",
+                elapsed, interval
+            );
+        }
+        if overruns.record(elapsed >= interval) {
+            let suggested = suggested_interval(elapsed);
+            if config.raise_interval_on_overrun {
+                warn!(
+                    "Operations keep taking longer than the polling interval ({:?} > {:?}), polling every {} seconds from now on",
+                    elapsed, interval, suggested
+                );
+                min_interval = suggested;
+            } else {
+                warn!(
+                    "Operations keep taking longer than the polling interval ({:?} > {:?}), consider setting polling_interval to at least {}",
+                    elapsed, interval, suggested
+                );
+            }
+        }
+
+        // Apply a requested reload from the next interval on, keeping the
+        // previous configuration if the file can't be loaded
+        if RELOAD_CONFIG.swap(false, Ordering::SeqCst) {
+            match AppConfig::new(args.config.clone()) {
+                Ok(new_config) => {
+                    info!("Reloaded configuration");
+                    let changed = changed_settings(&config, &new_config);
+                    if changed.is_empty() {
+                        debug!("No settings changed");
+                    } else {
+                        debug!("Changed settings: {}", changed.join(", "));
+                    }
+                    polling_interval = args.poll_time.unwrap_or(new_config.polling_interval);
+                    quiet_windows = new_config.quiet_windows();
+                    window.set_length(Duration::from_secs_f64(new_config.aggregation_window));
+                    if let Ok(mut state_guard) = input_state.lock() {
+                        state_guard.options = new_config.input_options();
+                    }
+                    outgoing.send(Outgoing::Reconfigure(new_config.sender_settings()));
+                    config = new_config;
+                }
+                Err(e) => warn!(
+                    "Error reloading configuration, keeping the previous one: {}",
+                    e
+                ),
+            }
+        }
+
+        // Where the power source can't be told, poll as if on AC power
+        let was_on_battery = on_battery;
+        on_battery = config.battery_polling_interval > 0.0 && power.on_battery().unwrap_or(false);
+        if on_battery && !was_on_battery {
+            info!(
+                "Running on battery, polling every {} seconds",
+                config.battery_polling_interval
+            );
+        } else if was_on_battery && !on_battery {
+            info!(
+                "No longer on battery, polling every {} seconds",
+                polling_interval
+            );
+        }
+        let base_interval = if on_battery {
+            config.battery_polling_interval
+        } else {
+            polling_interval
+        }
+        .max(min_interval);
+
+        // Switch between the fast and the idle interval, restarting the grid
+        // from the end of this interval so the next event tiles with this one
+        let next_interval =
+            Duration::from_secs_f64(adaptive_interval(base_interval, &config, empty_intervals));
+        idle_polling = next_interval != Duration::from_secs_f64(base_interval);
+        if next_interval != interval {
+            debug!("Polling every {:?} from now on", next_interval);
+            interval = next_interval;
+            next_tick = interval_instant + interval;
+        }
+        pulsetime = args
+            .pulsetime
+            .unwrap_or(interval.as_secs_f64() + config.pulsetime_margin);
+    }
+
+    notifier.stopping();
+
+    #[cfg(unix)]
+    if let Some(path) = &status_socket {
+        std::fs::remove_file(path).ok();
+    }
+
+    // Queued behind the final interval, so it is delivered before the sender stops
+    if config.session_events {
+        outgoing.send(Outgoing::Input(session_event("session_stop"), 0.0));
+    }
+
+    // Closing the outbox lets the sender thread deliver what is left and stop,
+    // or keep it for later once that takes too long
+    outgoing.close(sender::SHUTDOWN_TIMEOUT);
+    if sender_thread.join().is_err() {
+        error!("The heartbeat sender thread panicked");
+    }
+
+    if sink_kinds.contains(&SinkKind::AwServer) {
+        info!(
+            sent = metrics::get(&metrics::HEARTBEATS_SENT),
+            failed = metrics::get(&metrics::HEARTBEATS_FAILED);
+            "Sent {} heartbeats, {} failures",
+            metrics::get(&metrics::HEARTBEATS_SENT),
+            metrics::get(&metrics::HEARTBEATS_FAILED)
+        );
+    }
+
+    if let Some(Ok(totals)) = lifetime_totals.as_ref().map(|totals| totals.lock()) {
+        info!(
+            "Lifetime totals: {} presses, {} clicks, {:.0} pixels of mouse movement",
+            totals.presses, totals.clicks, totals.distance
+        );
+    }
+
+    info!("Graceful shutdown complete.");
+    if listener_failed {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totals_are_kept_next_to_the_given_config() {
+        assert_eq!(
+            totals_path(Some(Path::new("/tmp/watcher/config.toml"))),
+            Some(PathBuf::from("/tmp/watcher/totals.json"))
+        );
+    }
+
+    #[test]
+    fn lock_is_kept_next_to_the_given_config() {
+        assert_eq!(
+            lock_path(Some(Path::new("/tmp/watcher/config.toml")), "input_host"),
+            Some(PathBuf::from("/tmp/watcher/input_host.lock"))
+        );
+    }
+
+    #[test]
+    fn changed_settings_lists_only_the_differences() {
+        let old = AppConfig::default();
+        assert!(changed_settings(&old, &AppConfig::default()).is_empty());
+
+        let new = AppConfig {
+            polling_interval: 2.5,
+            ..Default::default()
+        };
+        assert_eq!(
+            changed_settings(&old, &new),
+            vec![format!(
+                "polling_interval: {} -> 2.5",
+                Value::from(old.polling_interval)
+            )]
+        );
+    }
+
+    #[test]
+    fn validate_rejects_zero_polling_interval() {
+        let config = AppConfig {
+            polling_interval: 0.0,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        let config = AppConfig {
+            polling_interval: f64::NAN,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        assert!(AppConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_zero_connect_timeout() {
+        let config = AppConfig {
+            connect_timeout: 0,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn python_compatible_data_matches_python_schema() {
+        let mut data = InputState::default();
+        data.presses = 12;
+        data.clicks = 3;
+        data.delta_x = 140;
+        data.delta_y = 80;
+        data.scroll_y = 4.0;
+        data.distance = 170.0;
+        data.corrections = 2;
+
+        // The event data the Python watcher sends for the same input
+        let mut python = Map::new();
+        for (key, value) in [
+            ("presses", 12u64),
+            ("clicks", 3),
+            ("deltaX", 140),
+            ("deltaY", 80),
+            ("scrollX", 0),
+            ("scrollY", 4),
+        ] {
+            python.insert(key.to_string(), Value::from(value));
+        }
+
+        assert_eq!(python_compatible_data(&data), python);
+    }
+
+    #[test]
+    fn validate_host_rejects_urls() {
+        assert!(validate_host("localhost").is_ok());
+        assert!(validate_host("aw.example.com").is_ok());
+        assert!(validate_host("http://localhost").is_err());
+        let error = validate_host("https://aw.example.com").unwrap_err();
+        assert!(error.contains("--scheme https"));
+    }
+
+    #[test]
+    fn parse_duration_accepts_units() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("10x").is_err());
+        assert!(parse_duration("1h30").is_err());
+    }
+
+    #[test]
+    fn parse_duration_rejects_overflow() {
+        let too_large = Err("duration too large".to_string());
+        assert_eq!(parse_duration("99999999999999999999"), too_large);
+        assert_eq!(parse_duration("99999999999999999999s"), too_large);
+        assert_eq!(parse_duration("999999999999999999d"), too_large);
+        assert_eq!(parse_duration(&format!("{}s1s", u64::MAX)), too_large);
+    }
+
+    #[test]
+    fn lock_instance_refuses_second_instance() {
+        let path = std::env::temp_dir().join(format!(
+            "aw-watcher-input-lock-test-{}.lock",
+            std::process::id()
+        ));
+
+        let lock = lock_instance(&path).unwrap();
+        let error = lock_instance(&path).unwrap_err();
+        assert!(error.contains(&std::process::id().to_string()));
+
+        // Dropping the handle releases the lock, like a process exiting
+        drop(lock);
+        assert!(lock_instance(&path).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn per_minute_handles_empty_intervals() {
+        assert_eq!(per_minute(3, TimeDelta::milliseconds(500)), 360.0);
+        assert_eq!(per_minute(3, TimeDelta::seconds(90)), 2.0);
+        assert_eq!(per_minute(3, TimeDelta::zero()), 0.0);
+    }
+
+    #[test]
+    fn last_tick_covers_overrun_intervals() {
+        let start = Instant::now();
+        let interval = Duration::from_millis(500);
+        let next_tick = start + interval;
+
+        // On time, the interval ends on the scheduled tick
+        let now = next_tick + Duration::from_millis(20);
+        assert_eq!(last_tick(next_tick, interval, now), next_tick);
+
+        // After an overrun the interval stretches over the missed ticks and
+        // the next one picks up where it ended
+        let now = start + Duration::from_millis(1700);
+        let end = last_tick(next_tick, interval, now);
+        assert_eq!(end - start, Duration::from_millis(1500));
+        assert_eq!(
+            last_tick(end + interval, interval, now + interval),
+            end + interval
+        );
+    }
+
+    #[test]
+    fn selected_sinks_follow_command_line() {
+        use SinkKind::*;
+        assert_eq!(selected_sinks(&[AwServer], false, false, false), [AwServer]);
+        assert_eq!(
+            selected_sinks(&[AwServer, Sqlite], true, false, false),
+            [Sqlite, Stdout]
+        );
+        assert_eq!(selected_sinks(&[AwServer], false, true, true), [Jsonl]);
+        assert_eq!(
+            selected_sinks(&[Jsonl, AwServer], false, false, true),
+            [Jsonl, AwServer]
+        );
+        assert!(selected_sinks(&[AwServer], false, true, false).is_empty());
+    }
+
+    #[test]
+    fn bucket_metadata_describes_the_watcher() {
+        let data = bucket_metadata(CaptureMode::Grab);
+        assert_eq!(data.get("os"), Some(&Value::from(std::env::consts::OS)));
+        assert_eq!(
+            data.get("watcher_version"),
+            Some(&Value::from(env!("CARGO_PKG_VERSION")))
+        );
+        assert!(matches!(
+            data.get("capture_mode").and_then(Value::as_str),
+            Some("grab" | "mock")
+        ));
+    }
+
+    #[test]
+    #[cfg(not(feature = "mock_input"))]
+    fn capture_mode_name_reports_the_fallback() {
+        assert_eq!(capture_mode_name(CaptureMode::Grab, false), "grab");
+        assert_eq!(capture_mode_name(CaptureMode::Grab, true), "listen");
+        assert_eq!(capture_mode_name(CaptureMode::Listen, false), "listen");
+    }
+
+    #[test]
+    fn parse_capture_mode_accepts_listen_and_grab() {
+        assert_eq!(parse_capture_mode("listen"), Ok(CaptureMode::Listen));
+        assert_eq!(parse_capture_mode("grab"), Ok(CaptureMode::Grab));
+        assert!(parse_capture_mode("Grab").is_err());
+    }
+
+    #[test]
+    fn overrun_tracker_reports_a_streak_once() {
+        let mut overruns = OverrunTracker::default();
+        for _ in 1..OVERRUN_STREAK {
+            assert!(!overruns.record(true));
+        }
+        assert!(overruns.record(true));
+        assert!(!overruns.record(true));
+
+        // A cycle on time ends the streak
+        assert!(!overruns.record(false));
+        for _ in 1..OVERRUN_STREAK {
+            assert!(!overruns.record(true));
+        }
+        assert!(overruns.record(true));
+    }
+
+    #[test]
+    fn suggested_interval_leaves_headroom() {
+        assert_eq!(suggested_interval(Duration::from_millis(1000)), 1.5);
+        assert_eq!(suggested_interval(Duration::from_millis(130)), 0.2);
+    }
+
+    #[test]
+    fn adaptive_interval_slows_down_when_idle() {
+        let config = AppConfig {
+            idle_polling_interval: 30.0,
+            idle_after_intervals: 3,
+            ..AppConfig::default()
+        };
+        assert_eq!(adaptive_interval(1.0, &config, 2), 1.0);
+        assert_eq!(adaptive_interval(1.0, &config, 3), 30.0);
+        assert_eq!(adaptive_interval(1.0, &config, 0), 1.0);
+
+        // Disabled by default
+        assert_eq!(adaptive_interval(1.0, &AppConfig::default(), 1000), 1.0);
+    }
+
+    #[test]
+    fn sanitize_hostname_normalizes_bucket_names() {
+        assert_eq!(sanitize_hostname("my-laptop"), "my-laptop");
+        assert_eq!(sanitize_hostname("My-Laptop.local"), "my-laptop-local");
+        assert_eq!(sanitize_hostname("Bürorechner"), "b-rorechner");
+        assert_eq!(sanitize_hostname("..."), "unknown-host");
+        assert_eq!(sanitize_hostname("host\u{FFFD}name"), "host-name");
+    }
+
+    #[test]
+    fn sanitize_hostname_truncates_to_a_dns_label() {
+        let long = "a".repeat(80);
+        assert_eq!(sanitize_hostname(&long), "a".repeat(MAX_HOSTNAME_LENGTH));
+
+        // No trailing dash is left where the cut falls after a separator
+        let dotted = format!("{}.local", "b".repeat(62));
+        assert_eq!(sanitize_hostname(&dotted), "b".repeat(62));
+    }
+
+    #[test]
+    fn session_event_is_a_zero_length_marker() {
+        let event = session_event("session_start");
+        assert_eq!(event.duration, TimeDelta::zero());
+        assert_eq!(event.data.len(), 1);
+        assert_eq!(event.data.get("event"), Some(&Value::from("session_start")));
+    }
+
+    #[test]
+    fn clock_jump_ignores_jitter() {
+        let interval = Duration::from_secs(1);
+        assert_eq!(clock_jump(TimeDelta::milliseconds(1020), interval), None);
+        assert_eq!(
+            clock_jump(TimeDelta::seconds(-4), interval),
+            Some(TimeDelta::seconds(-5))
+        );
+        assert_eq!(
+            clock_jump(TimeDelta::seconds(6), interval),
+            Some(TimeDelta::seconds(5))
+        );
+    }
+
+    #[test]
+    fn quiet_window_crosses_midnight() {
+        let evening = QuietWindow::parse("22:00-07:00").unwrap();
+        assert!(evening.contains(23 * 60));
+        assert!(evening.contains(6 * 60 + 59));
+        assert!(!evening.contains(7 * 60));
+        assert!(!evening.contains(12 * 60));
+
+        let lunch = QuietWindow::parse("12:00 - 13:30").unwrap();
+        assert!(lunch.contains(12 * 60));
+        assert!(!lunch.contains(13 * 60 + 30));
+
+        assert!(QuietWindow::parse("12:00-12:00").is_err());
+        assert!(QuietWindow::parse("25:00-07:00").is_err());
+        assert!(QuietWindow::parse("22:00").is_err());
+    }
+
+    #[test]
+    fn jitter_stays_below_the_maximum() {
+        assert_eq!(jitter(Duration::ZERO), Duration::ZERO);
+        let max = Duration::from_millis(250);
+        for _ in 0..100 {
+            assert!(jitter(max) < max);
+        }
+    }
 }
@@ -0,0 +1,161 @@
+//! Process-lifetime counters, separate from the per-interval data sent to
+//! aw-server. With the `metrics` feature they can be scraped by Prometheus.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Key presses recorded since startup
+pub static PRESSES: AtomicU64 = AtomicU64::new(0);
+/// Mouse clicks recorded since startup
+pub static CLICKS: AtomicU64 = AtomicU64::new(0);
+/// Input heartbeats the server accepted
+pub static HEARTBEATS_SENT: AtomicU64 = AtomicU64::new(0);
+/// Input heartbeats that still failed after all retries
+pub static HEARTBEATS_FAILED: AtomicU64 = AtomicU64::new(0);
+/// Events waiting in the queue for the server to come back
+pub static QUEUE_DEPTH: AtomicU64 = AtomicU64::new(0);
+/// 1 while tracking is paused, 0 otherwise
+pub static PAUSED: AtomicU64 = AtomicU64::new(0);
+/// The most recent error sending a heartbeat
+static LAST_ERROR: Mutex<Option<String>> = Mutex::new(None);
+
+/// Add to a counter, saturating at `u64::MAX` rather than wrapping
+pub fn add(counter: &AtomicU64, value: u64) {
+    counter
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+            Some(count.saturating_add(value))
+        })
+        .ok();
+}
+
+/// Set a gauge
+pub fn set(gauge: &AtomicU64, value: u64) {
+    gauge.store(value, Ordering::Relaxed);
+}
+
+/// Current value of a counter
+pub fn get(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+/// Remember an error for the status socket
+pub fn set_last_error(error: String) {
+    if let Ok(mut last_error) = LAST_ERROR.lock() {
+        *last_error = Some(error);
+    }
+}
+
+/// The most recent error, if any
+#[cfg(unix)]
+pub fn last_error() -> Option<String> {
+    LAST_ERROR
+        .lock()
+        .ok()
+        .and_then(|last_error| last_error.clone())
+}
+
+/// All metrics in the Prometheus text exposition format
+#[cfg(feature = "metrics")]
+fn render() -> String {
+    let metrics = [
+        (
+            "aw_watcher_input_presses_total",
+            "counter",
+            "Key presses recorded since startup",
+            &PRESSES,
+        ),
+        (
+            "aw_watcher_input_clicks_total",
+            "counter",
+            "Mouse clicks recorded since startup",
+            &CLICKS,
+        ),
+        (
+            "aw_watcher_input_heartbeats_sent_total",
+            "counter",
+            "Input heartbeats accepted by aw-server",
+            &HEARTBEATS_SENT,
+        ),
+        (
+            "aw_watcher_input_heartbeats_failed_total",
+            "counter",
+            "Input heartbeats that failed after all retries",
+            &HEARTBEATS_FAILED,
+        ),
+        (
+            "aw_watcher_input_queue_depth",
+            "gauge",
+            "Events queued while aw-server is unreachable",
+            &QUEUE_DEPTH,
+        ),
+        (
+            "aw_watcher_input_paused",
+            "gauge",
+            "Whether tracking is paused by quiet hours or a signal",
+            &PAUSED,
+        ),
+    ];
+
+    let mut body = String::new();
+    for (name, kind, help, value) in metrics {
+        body.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {}\n",
+            get(value)
+        ));
+    }
+    body
+}
+
+/// Serve the metrics over HTTP on `port` of the loopback interface, answering
+/// every request with the current values
+#[cfg(feature = "metrics")]
+pub fn spawn_server(port: u16) -> std::io::Result<()> {
+    use log::debug;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            // The request itself doesn't matter, but reading it keeps clients
+            // from seeing a reset connection. A client that connects without
+            // sending anything only holds up the next one for a second.
+            let mut request = [0; 1024];
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+            let _ = stream.read(&mut request);
+
+            let body = render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            if let Err(e) = stream.write_all(response.as_bytes()) {
+                debug!("Error answering metrics request: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_lists_every_metric() {
+        add(&HEARTBEATS_SENT, 2);
+
+        let body = render();
+        assert!(body.contains("# TYPE aw_watcher_input_presses_total counter\n"));
+        assert!(body.contains("# TYPE aw_watcher_input_queue_depth gauge\n"));
+        let sent = body
+            .lines()
+            .find_map(|line| line.strip_prefix("aw_watcher_input_heartbeats_sent_total "))
+            .unwrap();
+        assert!(sent.parse::<u64>().unwrap() >= 2);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::input::{InputState, Tracking};
+use crate::{ListenerOptions, PAUSED, RUNNING};
+use log::debug;
+use rdev::{Button, EventType, Key};
+use std::fs;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Environment variable naming the script the mock listener replays
+pub const MOCK_EVENTS_ENV: &str = "AW_WATCHER_INPUT_MOCK_EVENTS";
+
+/// One line of a mock input script
+#[derive(Debug, PartialEq)]
+enum MockStep {
+    Event(EventType),
+    Sleep(Duration),
+}
+
+/// Parse a script line such as `key`, `click left`, `move 10 20`,
+/// `wheel 0 -3` or `sleep 250`
+fn parse_step(line: &str) -> Result<MockStep, String> {
+    let mut parts = line.split_whitespace();
+    let command = parts.next().unwrap_or_default();
+    let args: Vec<&str> = parts.collect();
+
+    let number = |index: usize| -> Result<f64, String> {
+        args.get(index)
+            .ok_or_else(|| format!("missing argument {} for '{}'", index + 1, command))?
+            .parse::<f64>()
+            .map_err(|e| format!("invalid argument for '{}': {}", command, e))
+    };
+
+    let step = match command {
+        "key" => MockStep::Event(EventType::KeyPress(Key::Unknown(0))),
+        "click" => {
+            let button = match args.first().copied().unwrap_or("left") {
+                "left" => Button::Left,
+                "right" => Button::Right,
+                "middle" => Button::Middle,
+                other => Button::Unknown(
+                    other
+                        .parse()
+                        .map_err(|_| format!("unknown mouse button '{}'", other))?,
+                ),
+            };
+            MockStep::Event(EventType::ButtonPress(button))
+        }
+        "move" => MockStep::Event(EventType::MouseMove {
+            x: number(0)?,
+            y: number(1)?,
+        }),
+        "wheel" => MockStep::Event(EventType::Wheel {
+            delta_x: number(0)? as i64,
+            delta_y: number(1)? as i64,
+        }),
+        "sleep" => MockStep::Sleep(Duration::from_millis(number(0)? as u64)),
+        other => return Err(format!("unknown command '{}'", other)),
+    };
+    Ok(step)
+}
+
+/// Start a thread that replays the script named by [`MOCK_EVENTS_ENV`] into
+/// `state` instead of listening to real input devices. Like the real listener
+/// it keeps running after the script ends, and `options` are ignored.
+pub fn create_input_listener_thread(
+    state: Arc<Mutex<InputState>>,
+    tracking: Tracking,
+    _options: ListenerOptions,
+) -> JoinHandle<Result<(), String>> {
+    thread::spawn(move || {
+        let Some(path) = std::env::var_os(MOCK_EVENTS_ENV) else {
+            return Err(format!("{} is not set", MOCK_EVENTS_ENV));
+        };
+        let script = fs::read_to_string(&path)
+            .map_err(|e| format!("Could not read mock input script {:?}: {}", path, e))?;
+
+        for (number, line) in script.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if !RUNNING.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+
+            match parse_step(line) {
+                Ok(MockStep::Sleep(duration)) => thread::sleep(duration),
+                Ok(MockStep::Event(event_type)) => {
+                    if !tracking.tracks(&event_type) {
+                        continue;
+                    }
+                    if let Ok(mut state_guard) = state.lock() {
+                        if tracking.activity_only || PAUSED.load(Ordering::Relaxed) {
+                            state_guard.record_activity(event_type);
+                        } else {
+                            state_guard.record(event_type);
+                        }
+                    }
+                }
+                Err(e) => return Err(format!("{:?}:{}: {}", path, number + 1, e)),
+            }
+        }
+        debug!("Mock input script finished");
+
+        while RUNNING.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_step_reads_each_command() {
+        assert_eq!(
+            parse_step("click right"),
+            Ok(MockStep::Event(EventType::ButtonPress(Button::Right)))
+        );
+        assert_eq!(
+            parse_step("wheel 0 -3"),
+            Ok(MockStep::Event(EventType::Wheel {
+                delta_x: 0,
+                delta_y: -3
+            }))
+        );
+        assert_eq!(
+            parse_step("sleep 250"),
+            Ok(MockStep::Sleep(Duration::from_millis(250)))
+        );
+        assert!(parse_step("move 10").is_err());
+        assert!(parse_step("jump").is_err());
+    }
+}
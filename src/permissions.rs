@@ -0,0 +1,33 @@
+//! Checks for the OS permissions that input capture depends on. Only macOS
+//! gates global input behind a permission, elsewhere capture is always allowed.
+
+#[cfg(all(target_os = "macos", feature = "macos_permission_check"))]
+#[link(name = "CoreGraphics", kind = "framework")]
+extern "C" {
+    fn CGPreflightListenEventAccess() -> bool;
+    fn CGRequestListenEventAccess() -> bool;
+}
+
+/// Whether the process may listen to global input events, on macOS this is
+/// the Input Monitoring permission
+pub fn input_monitoring_allowed() -> bool {
+    #[cfg(all(target_os = "macos", feature = "macos_permission_check"))]
+    {
+        // SAFETY: takes no arguments and only queries the permission state
+        unsafe { CGPreflightListenEventAccess() }
+    }
+    #[cfg(not(all(target_os = "macos", feature = "macos_permission_check")))]
+    {
+        true
+    }
+}
+
+/// Ask the OS to prompt for the permission, on macOS this also adds the
+/// watcher to the Input Monitoring list
+pub fn request_input_monitoring() {
+    #[cfg(all(target_os = "macos", feature = "macos_permission_check"))]
+    // SAFETY: takes no arguments, the prompt is shown asynchronously
+    unsafe {
+        CGRequestListenEventAccess();
+    }
+}
@@ -0,0 +1,57 @@
+//! Power source detection for polling at `battery_polling_interval` while on
+//! battery. Without the `battery` feature the power source is never known.
+
+#[cfg(feature = "battery")]
+use log::debug;
+
+/// Reads whether the machine currently runs on battery
+pub struct PowerSource {
+    #[cfg(feature = "battery")]
+    manager: Option<battery::Manager>,
+}
+
+#[cfg(feature = "battery")]
+impl PowerSource {
+    /// Connect to the platform's battery API, if there is one
+    pub fn open() -> Self {
+        let manager = battery::Manager::new()
+            .inspect_err(|e| debug!("Battery status is unavailable: {}", e))
+            .ok();
+        Self { manager }
+    }
+
+    /// Whether any battery is discharging, or `None` when there is no battery
+    /// or its status can't be read
+    pub fn on_battery(&self) -> Option<bool> {
+        let batteries = self.manager.as_ref()?.batteries().ok()?;
+        let states: Vec<_> = batteries
+            .filter_map(Result::ok)
+            .map(|battery| battery.state())
+            .collect();
+        if states.is_empty() {
+            return None;
+        }
+        Some(states.contains(&battery::State::Discharging))
+    }
+}
+
+#[cfg(not(feature = "battery"))]
+impl PowerSource {
+    pub fn open() -> Self {
+        Self {}
+    }
+
+    pub fn on_battery(&self) -> Option<bool> {
+        None
+    }
+}
+
+#[cfg(all(test, not(feature = "battery")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn power_source_is_unknown_without_the_feature() {
+        assert_eq!(PowerSource::open().on_battery(), None);
+    }
+}
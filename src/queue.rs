@@ -0,0 +1,109 @@
+//! Events that could not be delivered yet, kept in memory until the server
+//! is reachable again.
+
+use aw_models::Event;
+use log::warn;
+use std::collections::VecDeque;
+
+/// Bounded queue of events that could not be delivered to the server yet,
+/// each kept with the pulsetime it is sent with
+pub struct EventQueue {
+    events: VecDeque<(Event, f64)>,
+    max_len: usize,
+}
+
+impl EventQueue {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            events: VecDeque::new(),
+            // The queue always has room for at least the event being sent
+            max_len: max_len.max(1),
+        }
+    }
+
+    /// Add an event to the back of the queue, dropping the oldest events when full
+    pub fn push(&mut self, event: Event, pulsetime: f64) {
+        while self.events.len() >= self.max_len {
+            if let Some((dropped, _)) = self.events.pop_front() {
+                warn!(
+                    "Event queue is full, dropping event from {}",
+                    dropped.timestamp
+                );
+            }
+        }
+        self.events.push_back((event, pulsetime));
+    }
+
+    /// Send queued events oldest first, stopping at the first failure so the
+    /// remaining events stay queued in timestamp order
+    pub fn flush<E>(
+        &mut self,
+        mut send: impl FnMut(&Event, f64) -> Result<(), E>,
+    ) -> Result<(), E> {
+        while let Some((event, pulsetime)) = self.events.front() {
+            send(event, *pulsetime)?;
+            self.events.pop_front();
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeDelta, Utc};
+    use serde_json::Map;
+
+    fn test_event(seconds: i64) -> Event {
+        Event {
+            id: None,
+            timestamp: Utc::now() + TimeDelta::seconds(seconds),
+            duration: TimeDelta::seconds(1),
+            data: Map::new(),
+        }
+    }
+
+    #[test]
+    fn event_queue_drops_oldest_when_full() {
+        let mut queue = EventQueue::new(2);
+        let events: Vec<Event> = (0..3).map(test_event).collect();
+        for event in &events {
+            queue.push(event.clone(), 1.1);
+        }
+
+        let mut sent = Vec::new();
+        let result: Result<(), ()> = queue.flush(|event, _| {
+            sent.push(event.clone());
+            Ok(())
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(sent, events[1..]);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn event_queue_keeps_events_after_failure() {
+        let mut queue = EventQueue::new(10);
+        for seconds in 0..3 {
+            queue.push(test_event(seconds), 1.1);
+        }
+
+        let mut attempts = 0;
+        let result = queue.flush(|_, _| {
+            attempts += 1;
+            if attempts > 1 {
+                Err("unreachable")
+            } else {
+                Ok(())
+            }
+        });
+
+        assert!(result.is_err());
+        assert_eq!(queue.len(), 2);
+    }
+}
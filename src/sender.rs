@@ -0,0 +1,408 @@
+//! Heartbeat delivery on a dedicated thread, so a slow or unreachable server
+//! never delays the next snapshot in the polling loop.
+
+use crate::sink::{Bucket, EventSink, Heartbeat, SenderSettings};
+use aw_models::Event;
+use log::{error, warn};
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How long the sender thread may keep delivering after shutdown started.
+/// Heartbeats still waiting then are handed to the sinks to keep for later.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Work for the sender thread
+pub enum Outgoing {
+    /// An input heartbeat with its pulsetime
+    Input(Event, f64),
+    /// An AFK heartbeat, with the pulsetime used while the status stays the same
+    Afk {
+        event: Event,
+        afk: bool,
+        pulsetime: f64,
+    },
+    /// A daily summary, sent without pulsetime
+    Daily(Event),
+    /// Start a fresh AFK event with the next heartbeat, e.g. after a sleep gap
+    AfkGap,
+    /// Settings reloaded from the config file
+    Reconfigure(SenderSettings),
+}
+
+/// Messages waiting for the sender thread
+struct Queue {
+    messages: VecDeque<Outgoing>,
+    /// Set once no more messages will arrive
+    closed: bool,
+    /// When the thread stops delivering and keeps the rest for later
+    deadline: Option<Instant>,
+}
+
+type Shared = Arc<(Mutex<Queue>, Condvar)>;
+
+/// Hands messages to the sender thread. At most `capacity` heartbeats wait at
+/// a time, so a server that never answers can't make them pile up while each
+/// send is stuck retrying.
+pub struct Outbox {
+    shared: Shared,
+    capacity: usize,
+}
+
+impl Outbox {
+    /// Queue a message, dropping the oldest input or AFK heartbeat when full
+    pub fn send(&self, message: Outgoing) {
+        let (queue, ready) = &*self.shared;
+        let mut queue = queue.lock().unwrap_or_else(PoisonError::into_inner);
+        if queue.messages.len() >= self.capacity {
+            let oldest = queue
+                .messages
+                .iter()
+                .position(|message| matches!(message, Outgoing::Input(..) | Outgoing::Afk { .. }));
+            if let Some(Outgoing::Input(event, _) | Outgoing::Afk { event, .. }) =
+                oldest.and_then(|index| queue.messages.remove(index))
+            {
+                warn!(
+                    "Sender is falling behind, dropping heartbeat from {}",
+                    event.timestamp
+                );
+            }
+        }
+        queue.messages.push_back(message);
+        ready.notify_one();
+    }
+
+    /// Let the sender thread deliver what is queued and stop, keeping what is
+    /// left after `timeout` for later
+    pub fn close(self, timeout: Duration) {
+        let (queue, _) = &*self.shared;
+        let mut queue = queue.lock().unwrap_or_else(PoisonError::into_inner);
+        queue.deadline = Some(Instant::now() + timeout);
+    }
+}
+
+impl Drop for Outbox {
+    fn drop(&mut self) {
+        let (queue, ready) = &*self.shared;
+        queue.lock().unwrap_or_else(PoisonError::into_inner).closed = true;
+        ready.notify_one();
+    }
+}
+
+/// The next message and whether the shutdown deadline passed, `None` once the
+/// outbox is closed and empty
+fn next_message(shared: &Shared) -> Option<(Outgoing, bool)> {
+    let (queue, ready) = &**shared;
+    let mut queue = queue.lock().unwrap_or_else(PoisonError::into_inner);
+    loop {
+        if let Some(message) = queue.messages.pop_front() {
+            let overdue = queue
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline);
+            return Some((message, overdue));
+        }
+        if queue.closed {
+            return None;
+        }
+        queue = ready.wait(queue).unwrap_or_else(PoisonError::into_inner);
+    }
+}
+
+/// Start the sender thread, with room for `capacity` waiting heartbeats. It
+/// handles messages in order until the [`Outbox`] is closed or dropped, so the
+/// events sent before shutdown are still delivered when the thread is joined.
+pub fn spawn_sender(sinks: Vec<Box<dyn EventSink>>, capacity: usize) -> (Outbox, JoinHandle<()>) {
+    let shared: Shared = Arc::new((
+        Mutex::new(Queue {
+            messages: VecDeque::new(),
+            closed: false,
+            deadline: None,
+        }),
+        Condvar::new(),
+    ));
+    let outbox = Outbox {
+        shared: Arc::clone(&shared),
+        capacity: capacity.max(1),
+    };
+    let handle = thread::spawn(move || {
+        let mut heartbeats = HeartbeatSender {
+            sinks,
+            last_afk: None,
+        };
+        heartbeats.run(&shared);
+    });
+    (outbox, handle)
+}
+
+struct HeartbeatSender {
+    sinks: Vec<Box<dyn EventSink>>,
+    /// AFK status sent last, used to detect status flips
+    last_afk: Option<bool>,
+}
+
+impl HeartbeatSender {
+    fn run(&mut self, shared: &Shared) {
+        let mut kept = 0;
+        while let Some((message, overdue)) = next_message(shared) {
+            if overdue {
+                kept += self.keep(message);
+                continue;
+            }
+            match message {
+                Outgoing::Input(event, pulsetime) => {
+                    self.fan_out(&Heartbeat {
+                        bucket: Bucket::Input,
+                        event: &event,
+                        pulsetime,
+                    });
+                }
+                Outgoing::Afk {
+                    event,
+                    afk,
+                    pulsetime,
+                } => self.send_afk(event, afk, pulsetime),
+                Outgoing::Daily(event) => {
+                    self.fan_out(&Heartbeat {
+                        bucket: Bucket::Daily,
+                        event: &event,
+                        pulsetime: 0.0,
+                    });
+                }
+                Outgoing::AfkGap => self.last_afk = None,
+                Outgoing::Reconfigure(settings) => {
+                    for sink in &mut self.sinks {
+                        sink.reconfigure(settings);
+                    }
+                }
+            }
+        }
+
+        if kept > 0 {
+            warn!(
+                "Shutdown timed out while sending, keeping {} heartbeats for later",
+                kept
+            );
+        }
+        for sink in &mut self.sinks {
+            sink.finish();
+        }
+    }
+
+    /// Hand a heartbeat that is too late to send to the sinks to keep,
+    /// returning how many heartbeats that was. The AFK status is only sent live.
+    fn keep(&mut self, message: Outgoing) -> usize {
+        let (bucket, event, pulsetime) = match message {
+            Outgoing::Input(event, pulsetime) => (Bucket::Input, event, pulsetime),
+            Outgoing::Daily(event) => (Bucket::Daily, event, 0.0),
+            _ => return 0,
+        };
+        let heartbeat = Heartbeat {
+            bucket,
+            event: &event,
+            pulsetime,
+        };
+        for sink in &mut self.sinks {
+            sink.keep(&heartbeat);
+        }
+        1
+    }
+
+    /// Hand the heartbeat to every sink, returning whether all of them took it
+    fn fan_out(&mut self, heartbeat: &Heartbeat) -> bool {
+        let mut delivered = true;
+        for sink in &mut self.sinks {
+            if let Err(e) = sink.send(heartbeat) {
+                error!("Error sending heartbeat to {}: {}", sink.name(), e);
+                delivered = false;
+            }
+        }
+        delivered
+    }
+
+    fn send_afk(&mut self, event: Event, afk: bool, pulsetime: f64) {
+        // Extend the current status with the long pulsetime, but start a
+        // fresh event whenever the status flips
+        let pulsetime = if self.last_afk == Some(afk) {
+            pulsetime
+        } else {
+            0.0
+        };
+
+        // Retry the flip with the next heartbeat if any sink missed it
+        if self.fan_out(&Heartbeat {
+            bucket: Bucket::Afk,
+            event: &event,
+            pulsetime,
+        }) {
+            self.last_afk = Some(afk);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeDelta, Utc};
+    use serde_json::Map;
+    use std::sync::{Arc, Mutex};
+
+    /// Records the pulsetimes it receives, failing the first `failures` sends
+    struct Recorder {
+        sent: Arc<Mutex<Vec<(Bucket, f64)>>>,
+        failures: usize,
+    }
+
+    impl EventSink for Recorder {
+        fn name(&self) -> &'static str {
+            "recorder"
+        }
+
+        fn send(&mut self, heartbeat: &Heartbeat) -> Result<(), String> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err("unreachable".to_string());
+            }
+            self.sent
+                .lock()
+                .unwrap()
+                .push((heartbeat.bucket, heartbeat.pulsetime));
+            Ok(())
+        }
+    }
+
+    /// Takes a second for every heartbeat, recording which were sent and
+    /// which kept for later
+    struct Slow {
+        sent: Arc<Mutex<usize>>,
+        kept: Arc<Mutex<usize>>,
+    }
+
+    impl EventSink for Slow {
+        fn name(&self) -> &'static str {
+            "slow"
+        }
+
+        fn send(&mut self, _heartbeat: &Heartbeat) -> Result<(), String> {
+            thread::sleep(Duration::from_secs(1));
+            *self.sent.lock().unwrap() += 1;
+            Ok(())
+        }
+
+        fn keep(&mut self, _heartbeat: &Heartbeat) {
+            *self.kept.lock().unwrap() += 1;
+        }
+    }
+
+    fn event() -> Event {
+        Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: TimeDelta::zero(),
+            data: Map::new(),
+        }
+    }
+
+    #[test]
+    fn failing_sink_does_not_block_the_others() {
+        let failing = Arc::new(Mutex::new(Vec::new()));
+        let working = Arc::new(Mutex::new(Vec::new()));
+        let (outgoing, handle) = spawn_sender(
+            vec![
+                Box::new(Recorder {
+                    sent: Arc::clone(&failing),
+                    failures: 1,
+                }),
+                Box::new(Recorder {
+                    sent: Arc::clone(&working),
+                    failures: 0,
+                }),
+            ],
+            16,
+        );
+        let afk = |afk| Outgoing::Afk {
+            event: event(),
+            afk,
+            pulsetime: 60.0,
+        };
+        outgoing.send(Outgoing::Input(event(), 1.1));
+        outgoing.send(afk(false));
+        outgoing.send(afk(false));
+        drop(outgoing);
+        handle.join().unwrap();
+
+        assert_eq!(
+            *working.lock().unwrap(),
+            vec![
+                (Bucket::Input, 1.1),
+                (Bucket::Afk, 0.0),
+                (Bucket::Afk, 60.0)
+            ]
+        );
+        assert_eq!(
+            *failing.lock().unwrap(),
+            vec![(Bucket::Afk, 0.0), (Bucket::Afk, 60.0)]
+        );
+    }
+
+    #[test]
+    fn full_outbox_drops_the_oldest_heartbeat() {
+        // Without a sender thread taking messages, the outbox fills up
+        let outbox = Outbox {
+            shared: Arc::new((
+                Mutex::new(Queue {
+                    messages: VecDeque::new(),
+                    closed: false,
+                    deadline: None,
+                }),
+                Condvar::new(),
+            )),
+            capacity: 2,
+        };
+        let at = |seconds| Event {
+            timestamp: Utc::now() + TimeDelta::seconds(seconds),
+            ..event()
+        };
+        let newest = at(2);
+        outbox.send(Outgoing::AfkGap);
+        outbox.send(Outgoing::Input(at(0), 1.0));
+        outbox.send(Outgoing::Input(at(1), 1.0));
+        outbox.send(Outgoing::Input(newest.clone(), 1.0));
+
+        // Messages other than heartbeats are never dropped
+        let shared = Arc::clone(&outbox.shared);
+        drop(outbox);
+        let mut timestamps = Vec::new();
+        while let Some((message, _)) = next_message(&shared) {
+            timestamps.push(match message {
+                Outgoing::Input(event, _) => Some(event.timestamp),
+                _ => None,
+            });
+        }
+        assert_eq!(timestamps, [None, Some(newest.timestamp)]);
+    }
+
+    #[test]
+    fn shutdown_keeps_heartbeats_after_the_timeout() {
+        let sent = Arc::new(Mutex::new(0));
+        let kept = Arc::new(Mutex::new(0));
+        let (outgoing, handle) = spawn_sender(
+            vec![Box::new(Slow {
+                sent: Arc::clone(&sent),
+                kept: Arc::clone(&kept),
+            })],
+            16,
+        );
+        for _ in 0..3 {
+            outgoing.send(Outgoing::Input(event(), 1.0));
+        }
+        let started = Instant::now();
+        outgoing.close(Duration::from_millis(500));
+        handle.join().unwrap();
+
+        // Only the heartbeat in flight when the time ran out was sent
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(*sent.lock().unwrap(), 1);
+        assert_eq!(*kept.lock().unwrap(), 2);
+    }
+}
@@ -0,0 +1,304 @@
+//! The aw-server requests made by the sender thread. aw-client-rust reports
+//! every response as a success whatever its status, so a deleted bucket or a
+//! failing server would go unnoticed. These requests go through reqwest
+//! directly and turn error statuses into a [`RequestError`].
+
+use crate::sink::{RequestError, SenderSettings};
+use aw_models::{Bucket, Event};
+use clap::ValueEnum;
+use reqwest::{Certificate, Url};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// How to connect to aw-server
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    /// Plain HTTP, fine for a server on the same machine
+    #[default]
+    Http,
+    /// HTTP over TLS, for a remote server
+    Https,
+}
+
+impl Scheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+        }
+    }
+}
+
+/// Root URL of the server at `host` and `port`, with IPv6 addresses in brackets
+pub fn base_url(scheme: Scheme, host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("{}://[{}]:{}", scheme.as_str(), host, port)
+    } else {
+        format!("{}://{}:{}", scheme.as_str(), host, port)
+    }
+}
+
+/// Read a PEM certificate to trust in addition to the system's roots, for a
+/// server with a self-signed certificate
+pub fn load_certificate(path: &Path) -> Result<Certificate, String> {
+    let pem = std::fs::read(path).map_err(|e| e.to_string())?;
+    Certificate::from_pem(&pem).map_err(|e| e.to_string())
+}
+
+/// Client for the aw-server REST API
+pub struct ServerApi {
+    client: reqwest::blocking::Client,
+    base_url: Url,
+    ca_cert: Option<Certificate>,
+}
+
+impl ServerApi {
+    /// Client for the server at `base_url`, also trusting `ca_cert` when given
+    pub fn new(
+        base_url: String,
+        ca_cert: Option<Certificate>,
+        settings: SenderSettings,
+    ) -> Result<Self, String> {
+        let base_url = Url::parse(&base_url).map_err(|e| e.to_string())?;
+        Ok(Self {
+            client: Self::client(ca_cert.as_ref(), settings)?,
+            base_url,
+            ca_cert,
+        })
+    }
+
+    /// Apply changed timeouts to the following requests
+    pub fn reconfigure(&mut self, settings: SenderSettings) -> Result<(), String> {
+        self.client = Self::client(self.ca_cert.as_ref(), settings)?;
+        Ok(())
+    }
+
+    fn client(
+        ca_cert: Option<&Certificate>,
+        settings: SenderSettings,
+    ) -> Result<reqwest::blocking::Client, String> {
+        let mut builder = reqwest::blocking::Client::builder()
+            .connect_timeout(settings.connect_timeout)
+            .timeout(settings.request_timeout);
+        if let Some(cert) = ca_cert {
+            builder = builder.add_root_certificate(cert.clone());
+        }
+        builder.build().map_err(|e| e.to_string())
+    }
+
+    /// URL of an API endpoint below `/api/0`, with each path segment escaped
+    /// so that a bucket ID can't point at another endpoint
+    fn url(&self, path: &[&str]) -> Url {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut()
+            .expect("http and https URLs have a path")
+            .pop_if_empty()
+            .extend(["api", "0"])
+            .extend(path);
+        url
+    }
+
+    /// Version of the server, to check that it is reachable
+    pub fn version(&self) -> Result<String, RequestError> {
+        let response = self
+            .client
+            .get(self.url(&["info"]))
+            .send()
+            .map_err(|e| RequestError::Network(e.to_string()))?;
+        let status = response.status();
+        let body = response.text().unwrap_or_default();
+        if status.is_client_error() || status.is_server_error() {
+            return Err(RequestError::new(Some(status.as_u16()), body));
+        }
+        let info: serde_json::Value = serde_json::from_str(&body)
+            .map_err(|e| RequestError::Network(format!("invalid server info: {}", e)))?;
+        Ok(info["version"].as_str().unwrap_or("unknown").to_string())
+    }
+
+    /// Send a heartbeat to `bucket_id`
+    pub fn heartbeat(
+        &self,
+        bucket_id: &str,
+        event: &Event,
+        pulsetime: f64,
+    ) -> Result<(), RequestError> {
+        let mut url = self.url(&["buckets", bucket_id, "heartbeat"]);
+        url.query_pairs_mut()
+            .append_pair("pulsetime", &pulsetime.to_string());
+        self.post(url, event)
+    }
+
+    /// Create `bucket`, which succeeds as well when it exists already
+    pub fn create_bucket(&self, bucket: &Bucket) -> Result<(), RequestError> {
+        self.post(self.url(&["buckets", &bucket.id]), bucket)
+    }
+
+    fn post(&self, url: Url, body: &impl Serialize) -> Result<(), RequestError> {
+        let response = self
+            .client
+            .post(url)
+            .json(body)
+            .send()
+            .map_err(|e| RequestError::Network(e.to_string()))?;
+        let status = response.status();
+        if status.is_client_error() || status.is_server_error() {
+            // aw-server explains the error in the body, e.g. which bucket is missing
+            let message = response.text().unwrap_or_default();
+            return Err(RequestError::new(Some(status.as_u16()), message));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use chrono::{TimeDelta, Utc};
+    use serde_json::Map;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Answer one connection per status in `statuses`, in order, after reading
+    /// the whole request
+    pub(crate) fn stub_server(statuses: &'static [&'static str]) -> ServerApi {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for (stream, status) in listener.incoming().zip(statuses) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut content_length = 0;
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|read| read > 2) {
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap_or(0);
+                        }
+                    }
+                    line.clear();
+                }
+                reader.read_exact(&mut vec![0; content_length]).ok();
+                let body = "{\"message\":\"error\"}";
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        ServerApi::new(base_url(Scheme::Http, "127.0.0.1", port), None, settings()).unwrap()
+    }
+
+    fn settings() -> SenderSettings {
+        SenderSettings {
+            retries: 0,
+            connect_timeout: Duration::from_secs(1),
+            request_timeout: Some(Duration::from_secs(1)),
+        }
+    }
+
+    fn event() -> Event {
+        Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: TimeDelta::zero(),
+            data: Map::new(),
+        }
+    }
+
+    #[test]
+    fn base_url_uses_the_scheme() {
+        assert_eq!(
+            base_url(Scheme::Http, "localhost", 5600),
+            "http://localhost:5600"
+        );
+        assert_eq!(
+            base_url(Scheme::Https, "aw.example.com", 443),
+            "https://aw.example.com:443"
+        );
+        assert_eq!(base_url(Scheme::Https, "::1", 5600), "https://[::1]:5600");
+    }
+
+    #[test]
+    fn bucket_ids_are_escaped_in_urls() {
+        let api =
+            ServerApi::new(base_url(Scheme::Http, "localhost", 5600), None, settings()).unwrap();
+        assert_eq!(
+            api.url(&["buckets", "a/b?c#d"]).as_str(),
+            "http://localhost:5600/api/0/buckets/a%2Fb%3Fc%23d"
+        );
+        assert_eq!(
+            api.url(&["info"]).as_str(),
+            "http://localhost:5600/api/0/info"
+        );
+    }
+
+    #[test]
+    fn error_statuses_become_request_errors() {
+        let api = stub_server(&["200 OK", "404 Not Found", "503 Service Unavailable"]);
+        let event = event();
+
+        assert_eq!(api.heartbeat("bucket", &event, 5.0), Ok(()));
+        let missing = api.heartbeat("bucket", &event, 5.0);
+        assert!(
+            matches!(missing, Err(RequestError::Client(404, _))),
+            "{:?}",
+            missing
+        );
+        let unavailable = api.heartbeat("bucket", &event, 5.0);
+        assert!(
+            matches!(unavailable, Err(RequestError::Server(503, _))),
+            "{:?}",
+            unavailable
+        );
+    }
+
+    #[test]
+    fn silent_server_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Accept the connection but never answer
+        thread::spawn(move || {
+            let _streams: Vec<_> = listener.incoming().take(1).collect();
+            thread::sleep(Duration::from_secs(5));
+        });
+        let api = ServerApi::new(
+            base_url(Scheme::Http, "127.0.0.1", port),
+            None,
+            SenderSettings {
+                request_timeout: Some(Duration::from_millis(100)),
+                ..settings()
+            },
+        )
+        .unwrap();
+        let result = api.heartbeat("bucket", &event(), 5.0);
+        assert!(
+            matches!(result, Err(RequestError::Network(_))),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn unreachable_server_is_a_network_error() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let api =
+            ServerApi::new(base_url(Scheme::Http, "127.0.0.1", port), None, settings()).unwrap();
+        let result = api.heartbeat("bucket", &event(), 5.0);
+        assert!(
+            matches!(result, Err(RequestError::Network(_))),
+            "{:?}",
+            result
+        );
+    }
+}
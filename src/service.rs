@@ -0,0 +1,209 @@
+//! LaunchAgent for starting the watcher at login on macOS, written by
+//! --install-service and removed by --uninstall-service.
+
+use dirs::home_dir;
+use std::fs::{create_dir_all, remove_file, write};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Label of the LaunchAgent, also the name of its plist file
+pub const LABEL: &str = "net.activitywatch.aw-watcher-input";
+
+/// Flags that only concern installing the service, left out of its arguments
+const INSTALL_FLAGS: [&str; 3] = [
+    "--install-service",
+    "--uninstall-service",
+    "--overwrite-service",
+];
+
+/// Flags taking a path, which is made absolute since launchd starts the
+/// service in `/`
+const PATH_FLAGS: [&str; 5] = [
+    "--config",
+    "--log-file",
+    "--output-file",
+    "--buffer-db",
+    "--ca-cert",
+];
+
+/// Location of the plist in the user's LaunchAgents directory
+pub fn plist_path() -> Option<PathBuf> {
+    home_dir().map(|dir| {
+        dir.join("Library")
+            .join("LaunchAgents")
+            .join(format!("{}.plist", LABEL))
+    })
+}
+
+/// Where launchd sends the watcher's output, unless --log-file is among its arguments
+pub fn default_log_path() -> Option<PathBuf> {
+    home_dir().map(|dir| {
+        dir.join("Library")
+            .join("Logs")
+            .join("aw-watcher-input.log")
+    })
+}
+
+/// The arguments the watcher was started with, apart from the install flags,
+/// so the service runs with the same options. Relative paths are resolved
+/// against `cwd`.
+pub fn service_arguments(args: impl Iterator<Item = String>, cwd: &Path) -> Vec<String> {
+    let absolute = |path: &str| cwd.join(path).display().to_string();
+    let mut arguments = Vec::new();
+    let mut path_follows = false;
+    for arg in args {
+        if path_follows {
+            path_follows = false;
+            arguments.push(absolute(&arg));
+        } else if INSTALL_FLAGS.contains(&arg.as_str()) {
+            continue;
+        } else if PATH_FLAGS.contains(&arg.as_str()) {
+            path_follows = true;
+            arguments.push(arg);
+        } else if let Some((flag, path)) = arg
+            .split_once('=')
+            .filter(|(flag, _)| PATH_FLAGS.contains(flag))
+        {
+            arguments.push(format!("{}={}", flag, absolute(path)));
+        } else {
+            arguments.push(arg);
+        }
+    }
+    arguments
+}
+
+/// A LaunchAgent that starts `executable` with `arguments` at login, and again
+/// whenever it exits with an error
+pub fn launch_agent_plist(executable: &Path, arguments: &[String], log: Option<&Path>) -> String {
+    let mut program_arguments = format!(
+        "        <string>{}</string>\n",
+        escape(&executable.display().to_string())
+    );
+    for argument in arguments {
+        program_arguments.push_str(&format!("        <string>{}</string>\n", escape(argument)));
+    }
+    let log = log
+        .map(|path| {
+            let path = escape(&path.display().to_string());
+            format!(
+                "    <key>StandardOutPath</key>\n    <string>{}</string>\n    \
+                 <key>StandardErrorPath</key>\n    <string>{}</string>\n",
+                path, path
+            )
+        })
+        .unwrap_or_default();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{}</string>
+    <key>ProgramArguments</key>
+    <array>
+{}    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <dict>
+        <key>SuccessfulExit</key>
+        <false/>
+    </dict>
+{}</dict>
+</plist>
+"#,
+        LABEL, program_arguments, log
+    )
+}
+
+/// Write the plist to `path`, refusing to overwrite an existing one unless
+/// `overwrite` is set
+pub fn install(path: &Path, plist: &str, overwrite: bool) -> io::Result<()> {
+    if path.exists() && !overwrite {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "the service is already installed, pass --overwrite-service to replace it",
+        ));
+    }
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    write(path, plist)
+}
+
+/// Remove the plist at `path`
+pub fn uninstall(path: &Path) -> io::Result<()> {
+    remove_file(path)
+}
+
+/// Escape the characters XML gives a meaning to
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_arguments_leave_out_install_flags() {
+        let args = [
+            "--afk",
+            "--install-service",
+            "--poll-time",
+            "5",
+            "--overwrite-service",
+            "--force",
+        ];
+        assert_eq!(
+            service_arguments(args.iter().map(|arg| arg.to_string()), Path::new("/")),
+            ["--afk", "--poll-time", "5", "--force"]
+        );
+    }
+
+    #[test]
+    fn service_arguments_make_paths_absolute() {
+        let args = [
+            "--config",
+            "config.toml",
+            "--log-file=logs/aw.log",
+            "--output-file",
+            "/var/tmp/events.jsonl",
+            "--bucket-id",
+            "input",
+        ];
+        assert_eq!(
+            service_arguments(
+                args.iter().map(|arg| arg.to_string()),
+                Path::new("/Users/me")
+            ),
+            [
+                "--config",
+                "/Users/me/config.toml",
+                "--log-file=/Users/me/logs/aw.log",
+                "--output-file",
+                "/var/tmp/events.jsonl",
+                "--bucket-id",
+                "input",
+            ]
+        );
+    }
+
+    #[test]
+    fn launch_agent_plist_lists_escaped_arguments() {
+        let plist = launch_agent_plist(
+            Path::new("/Applications/aw-watcher-input-rs"),
+            &["--bucket-id".to_string(), "a&b".to_string()],
+            Some(Path::new("/tmp/aw.log")),
+        );
+        assert!(plist.contains("<string>net.activitywatch.aw-watcher-input</string>"));
+        assert!(plist.contains(
+            "        <string>/Applications/aw-watcher-input-rs</string>\n        \
+             <string>--bucket-id</string>\n        <string>a&amp;b</string>\n    </array>"
+        ));
+        assert!(plist.contains("<key>StandardErrorPath</key>\n    <string>/tmp/aw.log</string>"));
+    }
+}
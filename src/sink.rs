@@ -0,0 +1,677 @@
+//! Destinations for heartbeats. The sender thread hands every heartbeat to
+//! each configured sink, and a failing sink doesn't hold up the others.
+
+#[cfg(feature = "buffer_db")]
+use crate::buffer::{EventBuffer, EventLog};
+use crate::metrics;
+use crate::queue::EventQueue;
+use crate::server::ServerApi;
+use aw_models::{Bucket as ServerBucket, Event};
+use log::{error, info, warn};
+use std::fmt;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Bucket a heartbeat belongs to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bucket {
+    Input,
+    Afk,
+    Daily,
+}
+
+/// A heartbeat ready to be delivered
+pub struct Heartbeat<'a> {
+    pub bucket: Bucket,
+    pub event: &'a Event,
+    pub pulsetime: f64,
+}
+
+/// Settings that can change on a reload
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SenderSettings {
+    pub retries: u32,
+    pub connect_timeout: Duration,
+    pub request_timeout: Option<Duration>,
+}
+
+/// Somewhere heartbeats are delivered to
+pub trait EventSink: Send {
+    /// Name used in log messages
+    fn name(&self) -> &'static str;
+
+    /// Deliver a heartbeat
+    fn send(&mut self, heartbeat: &Heartbeat) -> Result<(), String>;
+
+    /// Keep a heartbeat that there is no time left to send on shutdown.
+    /// Local sinks are quick, so they just deliver it.
+    fn keep(&mut self, heartbeat: &Heartbeat) {
+        if let Err(e) = self.send(heartbeat) {
+            error!("Error sending heartbeat to {}: {}", self.name(), e);
+        }
+    }
+
+    /// Apply settings reloaded from the config file
+    fn reconfigure(&mut self, _settings: SenderSettings) {}
+
+    /// Called once after the last heartbeat, before the watcher exits
+    fn finish(&mut self) {}
+}
+
+/// Why a request to aw-server failed
+#[derive(Debug, Clone, PartialEq)]
+pub enum RequestError {
+    /// The server rejected the request with a 4xx status, such as 404 for a
+    /// bucket that doesn't exist
+    Client(u16, String),
+    /// The server failed with a 5xx status
+    Server(u16, String),
+    /// The server couldn't be reached or didn't answer in time
+    Network(String),
+}
+
+impl RequestError {
+    /// Classify an error by the HTTP status it came with, if any
+    pub fn new(status: Option<u16>, message: String) -> Self {
+        match status {
+            Some(status @ 400..=499) => RequestError::Client(status, message),
+            Some(status) => RequestError::Server(status, message),
+            None => RequestError::Network(message),
+        }
+    }
+
+    /// Whether trying again may succeed, which a rejected request never does
+    pub fn is_retryable(&self) -> bool {
+        !matches!(self, RequestError::Client(..))
+    }
+}
+
+impl From<String> for RequestError {
+    fn from(message: String) -> Self {
+        RequestError::Network(message)
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::Client(status, message) => {
+                write!(f, "rejected with status {}: {}", status, message)
+            }
+            RequestError::Server(status, message) => {
+                write!(f, "server error {}: {}", status, message)
+            }
+            RequestError::Network(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// Events that failed to send, replayed once the server is reachable
+pub enum Backlog {
+    /// Kept in memory and lost when the watcher exits
+    Memory(EventQueue),
+    /// Kept in a database that survives restarts
+    #[cfg(feature = "buffer_db")]
+    Database(EventBuffer),
+}
+
+impl Backlog {
+    fn push(&mut self, event: Event, pulsetime: f64) -> Result<(), String> {
+        match self {
+            Backlog::Memory(queue) => {
+                queue.push(event, pulsetime);
+                Ok(())
+            }
+            #[cfg(feature = "buffer_db")]
+            Backlog::Database(buffer) => buffer.push(&event, pulsetime),
+        }
+    }
+
+    fn flush(&mut self, send: impl FnMut(&Event, f64) -> Result<(), String>) -> Result<(), String> {
+        match self {
+            Backlog::Memory(queue) => queue.flush(send),
+            #[cfg(feature = "buffer_db")]
+            Backlog::Database(buffer) => buffer.flush(send),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Backlog::Memory(queue) => queue.len(),
+            #[cfg(feature = "buffer_db")]
+            Backlog::Database(buffer) => buffer.len(),
+        }
+    }
+}
+
+/// Wait after the first failed attempt to create a missing bucket again,
+/// doubled after every further failure up to [`RECREATE_MAX_BACKOFF`]
+const RECREATE_MIN_BACKOFF: Duration = Duration::from_secs(5);
+const RECREATE_MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Buckets created at startup, created again if they are deleted on the
+/// server. Failed attempts back off, so a server that keeps refusing isn't
+/// asked again with every heartbeat.
+pub struct BucketRecreation {
+    buckets: Vec<ServerBucket>,
+    /// No attempts before this time, set after an attempt failed
+    retry_at: Option<Instant>,
+    backoff: Duration,
+}
+
+impl BucketRecreation {
+    pub fn new(buckets: Vec<ServerBucket>) -> Self {
+        Self {
+            buckets,
+            retry_at: None,
+            backoff: RECREATE_MIN_BACKOFF,
+        }
+    }
+
+    /// The bucket to create again, unless it is unknown or still backing off
+    fn bucket(&self, id: &str, now: Instant) -> Option<&ServerBucket> {
+        if self.retry_at.is_some_and(|retry_at| now < retry_at) {
+            return None;
+        }
+        self.buckets.iter().find(|bucket| bucket.id == id)
+    }
+
+    fn failed(&mut self, now: Instant) {
+        self.retry_at = Some(now + self.backoff);
+        self.backoff = (self.backoff * 2).min(RECREATE_MAX_BACKOFF);
+    }
+
+    fn succeeded(&mut self) {
+        self.retry_at = None;
+        self.backoff = RECREATE_MIN_BACKOFF;
+    }
+}
+
+/// Run an operation, retrying it with exponential backoff (100ms, 200ms, 400ms, ...)
+/// up to `retries` times before returning the last error. Errors that aren't
+/// `retryable` are returned right away.
+fn with_retries<T, E: std::fmt::Display>(
+    retries: u32,
+    retryable: impl Fn(&E) -> bool,
+    mut operation: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut backoff = Duration::from_millis(100);
+    let mut attempt = 0;
+    loop {
+        match operation() {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < retries && retryable(&e) => {
+                warn!(
+                    "Attempt {} failed: {}, retrying in {:?}",
+                    attempt + 1,
+                    e,
+                    backoff
+                );
+                sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Most daily summaries kept while aw-server is unreachable, a month's worth
+pub const MAX_QUEUED_DAILY_SUMMARIES: usize = 31;
+
+/// Sends heartbeats to aw-server, queueing the input ones and daily summaries
+/// while it is unreachable
+pub struct AwServer {
+    pub api: ServerApi,
+    pub bucket_id: String,
+    pub afk_bucket_id: String,
+    pub daily_bucket_id: String,
+    pub recreation: BucketRecreation,
+    pub settings: SenderSettings,
+    pub backlog: Backlog,
+    /// Daily summaries that failed to send, sent again once a heartbeat gets through
+    pub daily_backlog: EventQueue,
+}
+
+impl AwServer {
+    /// Send a heartbeat to `bucket_id`, retrying server and network errors and
+    /// timing out as configured
+    fn heartbeat(
+        api: &ServerApi,
+        settings: SenderSettings,
+        bucket_id: &str,
+        event: &Event,
+        pulsetime: f64,
+    ) -> Result<(), RequestError> {
+        with_retries(settings.retries, RequestError::is_retryable, || {
+            api.heartbeat(bucket_id, event, pulsetime)
+        })
+    }
+
+    /// Send a heartbeat, recreating its bucket first if the server lost it
+    fn deliver(
+        api: &ServerApi,
+        settings: SenderSettings,
+        recreation: &mut BucketRecreation,
+        bucket_id: &str,
+        event: &Event,
+        pulsetime: f64,
+    ) -> Result<(), RequestError> {
+        let result = Self::heartbeat(api, settings, bucket_id, event, pulsetime);
+        let Err(RequestError::Client(404, _)) = result else {
+            return result;
+        };
+        let now = Instant::now();
+        let Some(bucket) = recreation.bucket(bucket_id, now) else {
+            return result;
+        };
+        warn!(
+            "Bucket {} is missing on aw-server, creating it again",
+            bucket_id
+        );
+        if let Err(e) = api.create_bucket(bucket) {
+            recreation.failed(now);
+            warn!(
+                "Error creating bucket {} again, next attempt in {:?}: {}",
+                bucket_id,
+                recreation.retry_at.map_or(Duration::ZERO, |at| at - now),
+                e
+            );
+            return Err(e);
+        }
+        recreation.succeeded();
+        Self::heartbeat(api, settings, bucket_id, event, pulsetime)
+    }
+
+    /// Send the queued daily summaries, oldest first
+    fn flush_daily(&mut self) -> Result<(), String> {
+        let (api, settings, recreation, bucket_id) = (
+            &self.api,
+            self.settings,
+            &mut self.recreation,
+            &self.daily_bucket_id,
+        );
+        self.daily_backlog
+            .flush(|event, pulsetime| {
+                Self::settle(Self::deliver(
+                    api, settings, recreation, bucket_id, event, pulsetime,
+                ))
+            })
+            .map_err(|e| format!("{}, {} daily summaries queued", e, self.daily_backlog.len()))
+    }
+
+    /// Count a delivered heartbeat, leaving an error only when sending
+    /// the event again could succeed
+    fn settle(result: Result<(), RequestError>) -> Result<(), String> {
+        match result {
+            Ok(()) => {
+                metrics::add(&metrics::HEARTBEATS_SENT, 1);
+                Ok(())
+            }
+            // Sending a rejected event again can't succeed, and keeping it
+            // would hold up every event queued behind it. Events for a
+            // missing bucket stay queued until it exists again.
+            Err(e @ RequestError::Client(status, _)) if status != 404 => {
+                error!("Dropping a heartbeat that aw-server {}", e);
+                metrics::add(&metrics::HEARTBEATS_FAILED, 1);
+                metrics::set_last_error(format!("Heartbeat {}", e));
+                Ok(())
+            }
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+impl EventSink for AwServer {
+    fn name(&self) -> &'static str {
+        "aw-server"
+    }
+
+    fn send(&mut self, heartbeat: &Heartbeat) -> Result<(), String> {
+        match heartbeat.bucket {
+            Bucket::Input => {}
+            Bucket::Afk => {
+                return Self::deliver(
+                    &self.api,
+                    self.settings,
+                    &mut self.recreation,
+                    &self.afk_bucket_id,
+                    heartbeat.event,
+                    heartbeat.pulsetime,
+                )
+                .map_err(|e| e.to_string());
+            }
+            // A summary is only sent once, at rollover, so it is queued until
+            // the server takes it
+            Bucket::Daily => {
+                self.daily_backlog
+                    .push(heartbeat.event.clone(), heartbeat.pulsetime);
+                return self.flush_daily();
+            }
+        }
+
+        let replaying = self.backlog.len();
+        let (api, settings, recreation, bucket_id) = (
+            &self.api,
+            self.settings,
+            &mut self.recreation,
+            &self.bucket_id,
+        );
+        let result = if replaying == 0 {
+            // With nothing queued the heartbeat goes out directly, and is only
+            // queued when it failed in a way that sending it again can fix
+            let result = Self::settle(Self::deliver(
+                api,
+                settings,
+                recreation,
+                bucket_id,
+                heartbeat.event,
+                heartbeat.pulsetime,
+            ));
+            if result.is_err() {
+                self.backlog
+                    .push(heartbeat.event.clone(), heartbeat.pulsetime)?;
+            }
+            result
+        } else {
+            // Send the heartbeat after the queued ones, so that replayed events
+            // keep their original timestamps and the server receives them in order
+            self.backlog
+                .push(heartbeat.event.clone(), heartbeat.pulsetime)?;
+            self.backlog.flush(|event, pulsetime| {
+                Self::settle(Self::deliver(
+                    api, settings, recreation, bucket_id, event, pulsetime,
+                ))
+            })
+        };
+        metrics::set(&metrics::QUEUE_DEPTH, self.backlog.len() as u64);
+        match result {
+            Ok(()) => {
+                if replaying > 0 {
+                    info!("Reconnected, replayed {} queued events", replaying);
+                }
+                if self.daily_backlog.len() > 0 {
+                    if let Err(e) = self.flush_daily() {
+                        warn!("Error sending the queued daily summaries: {}", e);
+                    }
+                }
+                Ok(())
+            }
+            Err(e) => {
+                metrics::add(&metrics::HEARTBEATS_FAILED, 1);
+                metrics::set_last_error(format!("Error sending heartbeat: {}", e));
+                Err(format!("{}, {} events queued", e, self.backlog.len()))
+            }
+        }
+    }
+
+    fn keep(&mut self, heartbeat: &Heartbeat) {
+        let kept = match heartbeat.bucket {
+            Bucket::Input => self
+                .backlog
+                .push(heartbeat.event.clone(), heartbeat.pulsetime),
+            Bucket::Afk => Ok(()),
+            Bucket::Daily => {
+                self.daily_backlog
+                    .push(heartbeat.event.clone(), heartbeat.pulsetime);
+                Ok(())
+            }
+        };
+        if let Err(e) = kept {
+            error!("Error keeping a heartbeat for later: {}", e);
+        }
+    }
+
+    fn reconfigure(&mut self, settings: SenderSettings) {
+        if settings == self.settings {
+            return;
+        }
+        if let Err(e) = self.api.reconfigure(settings) {
+            warn!(
+                "Error applying the new timeouts, keeping the old ones: {}",
+                e
+            );
+        }
+        self.settings = settings;
+    }
+
+    fn finish(&mut self) {
+        if self.daily_backlog.len() > 0 {
+            warn!(
+                "Shutting down with {} daily summaries that could not be sent",
+                self.daily_backlog.len()
+            );
+        }
+        match &self.backlog {
+            Backlog::Memory(queue) if queue.len() > 0 => warn!(
+                "Shutting down with {} events that could not be sent",
+                queue.len()
+            ),
+            #[cfg(feature = "buffer_db")]
+            Backlog::Database(buffer) if buffer.len() > 0 => info!(
+                "Shutting down with {} events buffered for the next run",
+                buffer.len()
+            ),
+            _ => {}
+        }
+    }
+}
+
+/// Prints heartbeats to stdout, as used by --dry-run
+pub struct Stdout {
+    pub bucket_id: String,
+    pub afk_bucket_id: String,
+    pub daily_bucket_id: String,
+}
+
+impl EventSink for Stdout {
+    fn name(&self) -> &'static str {
+        "stdout"
+    }
+
+    fn send(&mut self, heartbeat: &Heartbeat) -> Result<(), String> {
+        let bucket_id = match heartbeat.bucket {
+            Bucket::Input => &self.bucket_id,
+            Bucket::Afk => &self.afk_bucket_id,
+            Bucket::Daily => &self.daily_bucket_id,
+        };
+        print_dry_run(bucket_id, heartbeat.event, heartbeat.pulsetime);
+        Ok(())
+    }
+}
+
+/// Print the heartbeat that would have been sent, as used by --dry-run
+fn print_dry_run(bucket_id: &str, event: &Event, pulsetime: f64) {
+    let heartbeat = serde_json::json!({
+        "bucket_id": bucket_id,
+        "pulsetime": pulsetime,
+        "event": event,
+    });
+    match serde_json::to_string_pretty(&heartbeat) {
+        Ok(json) => println!("{}", json),
+        Err(e) => error!("Error serializing event: {}", e),
+    }
+}
+
+/// Appends input events to a file, one JSON object per line
+pub struct JsonLines {
+    file: File,
+}
+
+impl JsonLines {
+    /// Open `path` for appending, creating it and its directory if needed
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+}
+
+impl EventSink for JsonLines {
+    fn name(&self) -> &'static str {
+        "jsonl"
+    }
+
+    /// Write the event as a single line and flush it, so the file stays
+    /// complete up to the last interval even if the watcher is killed
+    fn send(&mut self, heartbeat: &Heartbeat) -> Result<(), String> {
+        if heartbeat.bucket != Bucket::Input {
+            return Ok(());
+        }
+        let mut line = serde_json::to_string(heartbeat.event).map_err(|e| e.to_string())?;
+        line.push('\n');
+        self.file
+            .write_all(line.as_bytes())
+            .and_then(|()| self.file.flush())
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(feature = "buffer_db")]
+impl EventSink for EventLog {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn send(&mut self, heartbeat: &Heartbeat) -> Result<(), String> {
+        if heartbeat.bucket != Bucket::Input {
+            return Ok(());
+        }
+        self.insert(heartbeat.event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::tests::stub_server;
+    use chrono::{TimeDelta, Utc};
+    use serde_json::Map;
+
+    #[test]
+    fn with_retries_gives_up_after_configured_attempts() {
+        let mut attempts = 0;
+        let result: Result<(), &str> = with_retries(
+            2,
+            |_| true,
+            || {
+                attempts += 1;
+                Err("unreachable")
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+
+        let mut attempts = 0;
+        let result = with_retries(
+            2,
+            |_| true,
+            || {
+                attempts += 1;
+                if attempts < 2 {
+                    Err("unreachable")
+                } else {
+                    Ok(attempts)
+                }
+            },
+        );
+        assert_eq!(result, Ok(2));
+
+        // Errors that retrying can't fix are returned right away
+        let mut attempts = 0;
+        let result: Result<(), &str> = with_retries(
+            2,
+            |e| *e != "rejected",
+            || {
+                attempts += 1;
+                Err("rejected")
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn request_errors_are_classified_by_status() {
+        let error = |status| RequestError::new(status, "failed".to_string());
+        assert_eq!(
+            error(Some(404)),
+            RequestError::Client(404, "failed".to_string())
+        );
+        assert_eq!(
+            error(Some(503)),
+            RequestError::Server(503, "failed".to_string())
+        );
+        assert_eq!(error(None), RequestError::Network("failed".to_string()));
+
+        assert!(!error(Some(400)).is_retryable());
+        assert!(error(Some(500)).is_retryable());
+        assert!(error(None).is_retryable());
+    }
+
+    #[test]
+    fn bucket_recreation_backs_off_after_failures() {
+        let mut recreation = BucketRecreation::new(Vec::new());
+        let now = Instant::now();
+        assert_eq!(recreation.retry_at, None);
+
+        recreation.failed(now);
+        assert_eq!(recreation.retry_at, Some(now + RECREATE_MIN_BACKOFF));
+        recreation.failed(now);
+        assert_eq!(recreation.retry_at, Some(now + RECREATE_MIN_BACKOFF * 2));
+        for _ in 0..10 {
+            recreation.failed(now);
+        }
+        assert_eq!(recreation.retry_at, Some(now + RECREATE_MAX_BACKOFF));
+
+        recreation.succeeded();
+        assert_eq!(recreation.retry_at, None);
+        assert_eq!(recreation.backoff, RECREATE_MIN_BACKOFF);
+    }
+
+    #[test]
+    fn daily_summary_is_sent_again_when_rollover_fails() {
+        let mut sink = AwServer {
+            api: stub_server(&["503 Service Unavailable", "200 OK", "200 OK"]),
+            bucket_id: "input".to_string(),
+            afk_bucket_id: "afk".to_string(),
+            daily_bucket_id: "daily".to_string(),
+            recreation: BucketRecreation::new(Vec::new()),
+            settings: SenderSettings {
+                retries: 0,
+                connect_timeout: Duration::from_secs(1),
+                request_timeout: Some(Duration::from_secs(1)),
+            },
+            backlog: Backlog::Memory(EventQueue::new(10)),
+            daily_backlog: EventQueue::new(MAX_QUEUED_DAILY_SUMMARIES),
+        };
+        let event = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: TimeDelta::zero(),
+            data: Map::new(),
+        };
+
+        let summary = Heartbeat {
+            bucket: Bucket::Daily,
+            event: &event,
+            pulsetime: 0.0,
+        };
+        assert!(sink.send(&summary).is_err());
+        assert_eq!(sink.daily_backlog.len(), 1);
+
+        // The next heartbeat that gets through takes the summary along
+        let input = Heartbeat {
+            bucket: Bucket::Input,
+            event: &event,
+            pulsetime: 5.0,
+        };
+        assert_eq!(sink.send(&input), Ok(()));
+        assert_eq!(sink.daily_backlog.len(), 0);
+    }
+}
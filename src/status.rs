@@ -0,0 +1,264 @@
+//! Status socket for inspecting a running watcher. Every connection gets one
+//! JSON object with the live counters and process stats, and is then closed.
+//! Besides the status, clients can `reset` the counters of the current interval
+//! or `flush` them to aw-server right away.
+
+use crate::totals::LifetimeTotals;
+use crate::{metrics, FLUSH_REQUESTED};
+use aw_watcher_input_rs::InputState;
+use log::debug;
+use serde_json::{Map, Value};
+use std::fs::{remove_file, set_permissions, DirBuilder, Permissions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+extern "C" {
+    fn getuid() -> u32;
+}
+
+/// How long a client has to send a command before it gets the plain status
+const COMMAND_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// What a client asked for in the first line it sent
+#[derive(Debug, PartialEq)]
+enum Command {
+    Status,
+    Reset,
+    Flush,
+    Unknown(String),
+}
+
+impl Command {
+    /// Parse a line sent by the client, an empty one asks for the status
+    fn parse(line: &str) -> Self {
+        match line.trim() {
+            "" | "status" => Command::Status,
+            "reset" => Command::Reset,
+            "flush" => Command::Flush,
+            other => Command::Unknown(other.to_string()),
+        }
+    }
+}
+
+/// Create `dir` for the socket with only the current user allowed in, or check
+/// that an existing one is that private. Inside it nobody else can connect to
+/// the socket, even in the moment before its own permissions are set, or swap
+/// it for another one.
+fn private_dir(dir: &Path) -> io::Result<()> {
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    let metadata = std::fs::metadata(dir)?;
+    // SAFETY: takes no arguments and can't fail
+    if metadata.uid() != unsafe { getuid() } {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} belongs to another user", dir.display()),
+        ));
+    }
+    if metadata.mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} is accessible to other users, its mode must be 700",
+                dir.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Listen on `path`, replacing a socket left behind by an earlier run. Only the
+/// current user may connect.
+pub fn spawn_server(
+    path: &Path,
+    state: Arc<Mutex<InputState>>,
+    totals: Option<Arc<Mutex<LifetimeTotals>>>,
+    started: Instant,
+) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        private_dir(dir)?;
+    }
+    match remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    set_permissions(path, Permissions::from_mode(0o600))?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            // Clients that only read get the status once the timeout passes
+            let mut line = String::new();
+            if stream.set_read_timeout(Some(COMMAND_TIMEOUT)).is_ok() {
+                if let Ok(reader) = stream.try_clone() {
+                    BufReader::new(reader).read_line(&mut line).ok();
+                }
+            }
+            let command = Command::parse(&line);
+
+            // Build the reply before writing, so a slow client never holds the lock
+            let lifetime = totals
+                .as_ref()
+                .and_then(|totals| totals.lock().ok().map(|totals| totals.clone()));
+            let current_status = || match state.lock() {
+                Ok(state_guard) => status_json(&state_guard, lifetime.as_ref(), started),
+                Err(_) => status_json(&InputState::default(), lifetime.as_ref(), started),
+            };
+            let reply = match command {
+                Command::Status => current_status(),
+                // Taken under the same lock as the main loop takes each interval,
+                // so the snapshot's input is never also sent in a heartbeat
+                Command::Reset => {
+                    let snapshot = match state.lock() {
+                        Ok(mut state_guard) => state_guard.take(),
+                        Err(_) => InputState::default(),
+                    };
+                    debug!("Counters reset through the status socket");
+                    let mut status = status_json(&snapshot, lifetime.as_ref(), started);
+                    status.insert("reset".to_string(), Value::from(true));
+                    status
+                }
+                // The main loop picks the request up within its sleep step and
+                // sends the interval so far like on shutdown. The counters are
+                // read first, since the main loop may take them right after.
+                Command::Flush => {
+                    let mut status = current_status();
+                    FLUSH_REQUESTED.store(true, Ordering::SeqCst);
+                    debug!("Flush requested through the status socket");
+                    status.insert("flush".to_string(), Value::from(true));
+                    status
+                }
+                Command::Unknown(command) => {
+                    let mut error = Map::new();
+                    error.insert(
+                        "error".to_string(),
+                        Value::from(format!("unknown command {}", command)),
+                    );
+                    error
+                }
+            };
+            let reply = serde_json::to_string(&Value::Object(reply)).unwrap_or_default();
+            if let Err(e) = writeln!(stream, "{}", reply) {
+                debug!("Error answering status request: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The counters of the interval so far, the lifetime totals and the process stats
+fn status_json(
+    state: &InputState,
+    totals: Option<&LifetimeTotals>,
+    started: Instant,
+) -> Map<String, Value> {
+    let mut input = Map::new();
+    input.insert("presses".to_string(), Value::from(state.presses));
+    input.insert("clicks".to_string(), Value::from(state.clicks));
+    input.insert("deltaX".to_string(), Value::from(state.delta_x));
+    input.insert("deltaY".to_string(), Value::from(state.delta_y));
+    input.insert("distance".to_string(), Value::from(state.distance));
+    input.insert("scrollX".to_string(), Value::from(state.scroll_x));
+    input.insert("scrollY".to_string(), Value::from(state.scroll_y));
+    input.insert("active_time".to_string(), Value::from(state.active_seconds));
+    input.insert(
+        "seconds_since_activity".to_string(),
+        Value::from(state.last_activity.elapsed().as_secs_f64()),
+    );
+
+    let mut status = Map::new();
+    status.insert("input".to_string(), Value::Object(input));
+    let lifetime = totals.map(|totals| {
+        let mut lifetime = Map::new();
+        lifetime.insert("presses".to_string(), Value::from(totals.presses));
+        lifetime.insert("clicks".to_string(), Value::from(totals.clicks));
+        lifetime.insert("distance".to_string(), Value::from(totals.distance));
+        Value::Object(lifetime)
+    });
+    status.insert("lifetime".to_string(), lifetime.unwrap_or(Value::Null));
+    status.insert(
+        "uptime_seconds".to_string(),
+        Value::from(started.elapsed().as_secs()),
+    );
+    status.insert(
+        "heartbeats_sent".to_string(),
+        Value::from(metrics::get(&metrics::HEARTBEATS_SENT)),
+    );
+    status.insert(
+        "heartbeats_failed".to_string(),
+        Value::from(metrics::get(&metrics::HEARTBEATS_FAILED)),
+    );
+    status.insert(
+        "queue_depth".to_string(),
+        Value::from(metrics::get(&metrics::QUEUE_DEPTH)),
+    );
+    status.insert(
+        "paused".to_string(),
+        Value::from(metrics::get(&metrics::PAUSED) != 0),
+    );
+    status.insert("last_error".to_string(), Value::from(metrics::last_error()));
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_directory_is_private() {
+        let dir =
+            std::env::temp_dir().join(format!("aw-watcher-input-status-{}", std::process::id()));
+        let socket_dir = dir.join("socket");
+        private_dir(&socket_dir).unwrap();
+        let mode = std::fs::metadata(&socket_dir).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        set_permissions(&socket_dir, Permissions::from_mode(0o755)).unwrap();
+        assert!(private_dir(&socket_dir).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn status_json_reports_live_counters() {
+        let mut state = InputState::default();
+        state.presses = 3;
+        state.clicks = 1;
+
+        let totals = LifetimeTotals {
+            presses: 100,
+            ..LifetimeTotals::default()
+        };
+
+        let status = status_json(&state, Some(&totals), Instant::now());
+        let Some(Value::Object(input)) = status.get("input") else {
+            panic!("missing input counters");
+        };
+        assert_eq!(input.get("presses"), Some(&Value::from(3u64)));
+        assert_eq!(input.get("clicks"), Some(&Value::from(1u64)));
+        assert!(status.contains_key("uptime_seconds"));
+        assert!(status.contains_key("last_error"));
+        assert_eq!(status.get("paused"), Some(&Value::from(false)));
+        let Some(Value::Object(lifetime)) = status.get("lifetime") else {
+            panic!("missing lifetime totals");
+        };
+        assert_eq!(lifetime.get("presses"), Some(&Value::from(100u64)));
+    }
+
+    #[test]
+    fn commands_are_parsed_from_the_first_line() {
+        assert_eq!(Command::parse(""), Command::Status);
+        assert_eq!(Command::parse("status\n"), Command::Status);
+        assert_eq!(Command::parse(" reset\r\n"), Command::Reset);
+        assert_eq!(Command::parse("flush\n"), Command::Flush);
+        assert_eq!(
+            Command::parse("quit\n"),
+            Command::Unknown("quit".to_string())
+        );
+    }
+}
@@ -0,0 +1,71 @@
+//! Readiness and watchdog notifications for running as a systemd service with
+//! `Type=notify`. Without the `systemd` feature, or outside Linux, nothing is
+//! ever sent.
+
+#[cfg(all(target_os = "linux", feature = "systemd"))]
+use log::{debug, info};
+#[cfg(all(target_os = "linux", feature = "systemd"))]
+use sd_notify::NotifyState;
+#[cfg(all(target_os = "linux", feature = "systemd"))]
+use std::time::{Duration, Instant};
+
+/// Connection to the service manager, through `NOTIFY_SOCKET`
+pub struct Notifier {
+    /// How often to ping the watchdog, half its timeout, and when it last was
+    #[cfg(all(target_os = "linux", feature = "systemd"))]
+    watchdog: Option<(Duration, Instant)>,
+}
+
+#[cfg(all(target_os = "linux", feature = "systemd"))]
+impl Notifier {
+    /// Find out from `WATCHDOG_USEC` whether systemd expects watchdog pings
+    pub fn connect() -> Self {
+        let mut usec = 0;
+        let watchdog = sd_notify::watchdog_enabled(false, &mut usec).then(|| {
+            let interval = Duration::from_micros(usec) / 2;
+            info!("Pinging the systemd watchdog every {:?}", interval);
+            (interval, Instant::now())
+        });
+        Self { watchdog }
+    }
+
+    /// Tell systemd that startup completed
+    pub fn ready(&self) {
+        Self::notify(&[NotifyState::Ready]);
+    }
+
+    /// Ping the watchdog when half its timeout passed since the last ping
+    pub fn watchdog(&mut self) {
+        if let Some((interval, last)) = &mut self.watchdog {
+            if last.elapsed() >= *interval {
+                *last = Instant::now();
+                Self::notify(&[NotifyState::Watchdog]);
+            }
+        }
+    }
+
+    /// Tell systemd that the watcher is shutting down
+    pub fn stopping(&self) {
+        Self::notify(&[NotifyState::Stopping]);
+    }
+
+    /// Outside a `Type=notify` service there is no socket, which isn't an error
+    fn notify(state: &[NotifyState]) {
+        if let Err(e) = sd_notify::notify(false, state) {
+            debug!("Error notifying systemd: {}", e);
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "systemd")))]
+impl Notifier {
+    pub fn connect() -> Self {
+        Self {}
+    }
+
+    pub fn ready(&self) {}
+
+    pub fn watchdog(&mut self) {}
+
+    pub fn stopping(&self) {}
+}
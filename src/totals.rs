@@ -0,0 +1,135 @@
+//! All-time input totals for the machine, kept in a small JSON file so they
+//! survive restarts, along with the totals of the current day for the daily
+//! summary. Only the daily summary is ever sent to aw-server.
+
+use aw_watcher_input_rs::InputState;
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, rename, write};
+use std::io;
+use std::path::Path;
+
+/// Running totals since the file was first created
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LifetimeTotals {
+    #[serde(default)]
+    pub presses: u64,
+    #[serde(default)]
+    pub clicks: u64,
+    #[serde(default)]
+    pub distance: f64,
+    /// Totals of the day being recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub today: Option<DayTotals>,
+}
+
+/// Totals of a single local day
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DayTotals {
+    /// Local date as YYYY-MM-DD
+    pub date: String,
+    #[serde(default)]
+    pub presses: u64,
+    #[serde(default)]
+    pub clicks: u64,
+    #[serde(default)]
+    pub distance: f64,
+}
+
+impl LifetimeTotals {
+    /// Read the totals from `path`, starting from zero when there is no file yet
+    pub fn load(path: &Path) -> Result<Self, String> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(|e| e.to_string()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// Write the totals to `path` through a temporary file, so a crash never
+    /// leaves a truncated file behind
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let contents = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        let temporary = path.with_extension("json.tmp");
+        write(&temporary, contents)
+            .and_then(|()| rename(&temporary, path))
+            .map_err(|e| e.to_string())
+    }
+
+    /// Add the counts of one interval, to the current day as well
+    pub fn add(&mut self, data: &InputState) {
+        self.presses = self.presses.saturating_add(data.presses);
+        self.clicks = self.clicks.saturating_add(data.clicks);
+        self.distance += data.distance;
+        if let Some(today) = &mut self.today {
+            today.presses = today.presses.saturating_add(data.presses);
+            today.clicks = today.clicks.saturating_add(data.clicks);
+            today.distance += data.distance;
+        }
+    }
+
+    /// Start counting `date` if it is a new day, returning the day before it.
+    /// A day left over from an earlier run continues if it is still `date`.
+    pub fn roll_over(&mut self, date: &str) -> Option<DayTotals> {
+        if self.today.as_ref().is_some_and(|today| today.date == date) {
+            return None;
+        }
+        let fresh = DayTotals {
+            date: date.to_string(),
+            ..DayTotals::default()
+        };
+        self.today.replace(fresh)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_accumulates_intervals() {
+        let mut totals = LifetimeTotals::default();
+        let mut data = InputState::default();
+        data.presses = 5;
+        data.clicks = 2;
+        data.distance = 10.5;
+        totals.add(&data);
+        totals.add(&data);
+
+        assert_eq!(
+            totals,
+            LifetimeTotals {
+                presses: 10,
+                clicks: 4,
+                distance: 21.0,
+                today: None,
+            }
+        );
+    }
+
+    #[test]
+    fn roll_over_returns_the_finished_day() {
+        let mut totals = LifetimeTotals::default();
+        let mut data = InputState::default();
+        data.presses = 5;
+
+        assert_eq!(totals.roll_over("2024-03-01"), None);
+        totals.add(&data);
+        assert_eq!(totals.roll_over("2024-03-01"), None);
+        totals.add(&data);
+
+        let finished = totals.roll_over("2024-03-02").unwrap();
+        assert_eq!(finished.date, "2024-03-01");
+        assert_eq!(finished.presses, 10);
+        assert_eq!(totals.today.as_ref().map(|today| today.presses), Some(0));
+        assert_eq!(totals.presses, 10);
+    }
+
+    #[test]
+    fn missing_file_starts_from_zero() {
+        let path = std::env::temp_dir().join("aw-watcher-input-test-missing-totals.json");
+        assert_eq!(LifetimeTotals::load(&path), Ok(LifetimeTotals::default()));
+    }
+}
@@ -0,0 +1,124 @@
+//! Rolling window over the most recent intervals, for rates that are smoother
+//! than the ones computed from a single short interval.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Counts of one interval, as kept in the window
+#[derive(Debug, Clone, Copy)]
+struct Snapshot {
+    duration: Duration,
+    presses: u64,
+    clicks: u64,
+    active_time: f64,
+}
+
+/// Rates over the intervals in the window
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WindowStats {
+    /// Time actually covered, shorter than the window right after startup
+    pub seconds: f64,
+    pub presses_per_minute: f64,
+    pub clicks_per_minute: f64,
+    /// Typing speed counting five key presses as a word, pauses included
+    pub wpm: f64,
+    pub active_time: f64,
+}
+
+/// The intervals of the last `length` of time, oldest first
+#[derive(Debug)]
+pub struct RollingWindow {
+    length: Duration,
+    snapshots: VecDeque<Snapshot>,
+}
+
+impl RollingWindow {
+    pub fn new(length: Duration) -> Self {
+        Self {
+            length,
+            snapshots: VecDeque::new(),
+        }
+    }
+
+    /// Change the window length, from the next interval on
+    pub fn set_length(&mut self, length: Duration) {
+        self.length = length;
+    }
+
+    /// Add an interval and drop the oldest ones that no longer fit. The latest
+    /// interval is always kept, even if it is longer than the window.
+    pub fn push(&mut self, duration: Duration, presses: u64, clicks: u64, active_time: f64) {
+        self.snapshots.push_back(Snapshot {
+            duration,
+            presses,
+            clicks,
+            active_time,
+        });
+        let mut covered: Duration = self.snapshots.iter().map(|s| s.duration).sum();
+        while self.snapshots.len() > 1 && covered > self.length {
+            if let Some(oldest) = self.snapshots.pop_front() {
+                covered = covered.saturating_sub(oldest.duration);
+            }
+        }
+    }
+
+    /// Totals of the window turned into rates
+    pub fn stats(&self) -> WindowStats {
+        let seconds: f64 = self
+            .snapshots
+            .iter()
+            .map(|s| s.duration.as_secs_f64())
+            .sum();
+        let presses: u64 = self.snapshots.iter().map(|s| s.presses).sum();
+        let clicks: u64 = self.snapshots.iter().map(|s| s.clicks).sum();
+        let per_minute = |count: u64| {
+            if seconds > 0.0 {
+                count as f64 * 60.0 / seconds
+            } else {
+                0.0
+            }
+        };
+        WindowStats {
+            seconds,
+            presses_per_minute: per_minute(presses),
+            clicks_per_minute: per_minute(clicks),
+            wpm: per_minute(presses) / 5.0,
+            active_time: self.snapshots.iter().map(|s| s.active_time).sum(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_keeps_only_recent_intervals() {
+        let mut window = RollingWindow::new(Duration::from_secs(60));
+        for _ in 0..90 {
+            window.push(Duration::from_secs(1), 5, 1, 1.0);
+        }
+        let stats = window.stats();
+        assert_eq!(stats.seconds, 60.0);
+        assert_eq!(stats.presses_per_minute, 300.0);
+        assert_eq!(stats.wpm, 60.0);
+        assert_eq!(stats.clicks_per_minute, 60.0);
+        assert_eq!(stats.active_time, 60.0);
+
+        // Idle intervals bring the rates down gradually
+        for _ in 0..30 {
+            window.push(Duration::from_secs(1), 0, 0, 0.0);
+        }
+        assert_eq!(window.stats().presses_per_minute, 150.0);
+    }
+
+    #[test]
+    fn window_keeps_an_interval_longer_than_itself() {
+        let mut window = RollingWindow::new(Duration::from_secs(10));
+        window.push(Duration::from_secs(1), 1, 0, 1.0);
+        window.push(Duration::from_secs(30), 30, 0, 5.0);
+        let stats = window.stats();
+        assert_eq!(stats.seconds, 30.0);
+        assert_eq!(stats.presses_per_minute, 60.0);
+    }
+}
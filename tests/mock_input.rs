@@ -0,0 +1,207 @@
+//! End-to-end run of the watcher against a mock aw-server, with input
+//! replayed from a script. Run with `cargo test --features mock_input`.
+#![cfg(feature = "mock_input")]
+
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Scripted input: three presses, two clicks, a 3 pixel move and two wheel steps
+const SCRIPT: &str =
+    "sleep 500\nkey\nkey\nkey\nclick left\nclick right\nmove 0 0\nmove 3 4\nwheel 0 -2\n";
+
+/// Totals of the scripted input, for the data fields in `TOTAL_KEYS`
+const SCRIPT_TOTALS: [u64; 4] = [3, 2, 3, 2];
+const TOTAL_KEYS: [&str; 4] = ["presses", "clicks", "deltaX", "scrollDown"];
+
+/// A request received by the mock server, and the status it was answered with
+struct Request {
+    method: String,
+    path: String,
+    body: String,
+    status: &'static str,
+}
+
+impl Request {
+    fn is_heartbeat(&self) -> bool {
+        self.path.starts_with("/api/0/buckets/mock-input/heartbeat")
+    }
+
+    fn is_bucket_creation(&self) -> bool {
+        self.method == "POST" && self.path == "/api/0/buckets/mock-input"
+    }
+}
+
+/// Answer a request with the status picked by `respond` and an empty JSON
+/// object, and forward it to `requests`
+fn handle_connection(
+    stream: TcpStream,
+    requests: &Sender<Request>,
+    respond: &mut impl FnMut(&str, &str) -> &'static str,
+) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).is_err() || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    if reader.read_exact(&mut body).is_err() {
+        return;
+    }
+
+    let status = respond(&method, &path);
+    let mut stream = stream;
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{{}}",
+        status
+    );
+    let _ = requests.send(Request {
+        method,
+        path,
+        body: String::from_utf8_lossy(&body).into_owned(),
+        status,
+    });
+}
+
+/// Start a mock aw-server on a free port, answering with `respond`
+fn mock_server(
+    mut respond: impl FnMut(&str, &str) -> &'static str + Send + 'static,
+) -> (u16, Receiver<Request>) {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = server.local_addr().unwrap().port();
+    let (sender, requests) = channel();
+    thread::spawn(move || {
+        for stream in server.incoming().flatten() {
+            handle_connection(stream, &sender, &mut respond);
+        }
+    });
+    (port, requests)
+}
+
+/// A temporary directory holding the config and the input script. Lifetime
+/// totals are turned off so that a run never writes outside the directory.
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "aw-watcher-input-mock-{}-{}",
+        name,
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("config.toml"),
+        "polling_interval = 1\nsingle_instance = false\nlifetime_totals = false\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("events.txt"), SCRIPT).unwrap();
+    dir
+}
+
+/// Run the watcher against the mock server on `port`
+fn spawn_watcher(dir: &Path, port: u16) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_aw-watcher-input-rs"))
+        .args(["--host", "127.0.0.1", "--port", &port.to_string()])
+        .args(["--bucket-id", "mock-input"])
+        .arg("--config")
+        .arg(dir.join("config.toml"))
+        .env("AW_WATCHER_INPUT_MOCK_EVENTS", dir.join("events.txt"))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap()
+}
+
+/// Input may be split across intervals, so sum accepted heartbeats until
+/// every scripted event has been reported, passing each request to `inspect`
+fn sum_heartbeats(requests: &Receiver<Request>, mut inspect: impl FnMut(&Request)) -> [u64; 4] {
+    let mut totals = [0u64; 4];
+    let deadline = Instant::now() + Duration::from_secs(15);
+    while totals != SCRIPT_TOTALS && Instant::now() < deadline {
+        let Ok(request) = requests.recv_timeout(Duration::from_millis(100)) else {
+            continue;
+        };
+        inspect(&request);
+        if !request.is_heartbeat() || request.status != "200 OK" {
+            continue;
+        }
+        let event: Value = serde_json::from_str(&request.body).unwrap();
+        let data = &event["data"];
+        for (total, key) in totals.iter_mut().zip(TOTAL_KEYS) {
+            *total += data[key].as_u64().unwrap();
+        }
+    }
+    totals
+}
+
+#[test]
+fn heartbeats_report_scripted_input() {
+    let dir = test_dir("input");
+    let (port, requests) = mock_server(|_, _| "200 OK");
+    let mut child = spawn_watcher(&dir, port);
+
+    let totals = sum_heartbeats(&requests, |_| {});
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(totals, SCRIPT_TOTALS);
+}
+
+#[test]
+fn deleted_bucket_is_created_again() {
+    let dir = test_dir("deleted");
+    // The bucket is deleted right after the watcher created it at startup, so
+    // heartbeats fail with 404 until it is created a second time
+    let mut creations = 0;
+    let (port, requests) = mock_server(move |method, path| {
+        if method == "POST" && path == "/api/0/buckets/mock-input" {
+            creations += 1;
+        } else if path.starts_with("/api/0/buckets/mock-input/heartbeat") && creations < 2 {
+            return "404 Not Found";
+        }
+        "200 OK"
+    });
+    let mut child = spawn_watcher(&dir, port);
+
+    let (mut missing, mut creations) = (0, 0);
+    let totals = sum_heartbeats(&requests, |request| {
+        if request.is_heartbeat() && request.status == "404 Not Found" {
+            missing += 1;
+        } else if request.is_bucket_creation() && missing > 0 {
+            creations += 1;
+        }
+    });
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(missing > 0, "no heartbeat reached the deleted bucket");
+    assert_eq!(creations, 1);
+    // The heartbeat that failed stayed queued and was sent once the bucket
+    // existed again, so no input was lost
+    assert_eq!(totals, SCRIPT_TOTALS);
+}